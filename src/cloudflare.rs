@@ -1,80 +1,429 @@
-use std::net::Ipv4Addr;
-
 use anyhow::{Context, Result};
 use cloudflare::{
-    endpoints::dns::dns::DnsContent,
-    framework::{Environment, client::async_api::Client},
+    endpoints::dns::dns::DeleteDnsRecord,
+    framework::{
+        Environment,
+        client::async_api::Client,
+        endpoint::{Method, RequestBody, serialize_query, spec::EndpointSpec},
+        response::{ApiFailure, ApiResult, ApiSuccess},
+    },
 };
+use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-use crate::{anyhow_tracing::Tracing, config::Config, state::State};
-
-#[instrument(skip(config, state))]
-pub async fn update_cloudflare(
-    config: &Config,
-    state: &mut Option<State>,
-    ip: Ipv4Addr,
-) -> Result<()> {
-    tracing::trace!("Updating Cloudflare");
-    let cf_config = config.get_cloudflare_config();
-    let cache_config = config.get_cache_config();
-
-    let client = Client::new(
-        cf_config.get_creds(),
-        Default::default(),
-        Environment::Production,
-    )
-    .context("failed to create new `cloudflare` client")
-    .debug()
-    .debug_success("Successfully created new `cloudflare` client")
-    .error()?;
-
-    let response = client
-        .request(&cf_config.get_list_request())
-        .await
-        .context("failed to request DNS record list from Cloudflare")
+use crate::{
+    anyhow_tracing::Tracing,
+    config::CloudflareConfig,
+    provider::{DnsProvider, Record, RecordContent, RecordNotFound, RecordParams},
+};
+
+/// Records fetched per page when listing
+const PAGE_SIZE: u32 = 5000;
+
+/// [`DnsProvider`] backed by the Cloudflare API
+pub struct CloudflareProvider {
+    client: Client,
+}
+
+impl CloudflareProvider {
+    #[instrument]
+    pub(crate) fn new(cf_config: &CloudflareConfig) -> Result<Self> {
+        let client = Client::new(
+            cf_config.get_creds(),
+            Default::default(),
+            Environment::Production,
+        )
+        .context("failed to create new `cloudflare` client")
         .debug()
-        .debug_success("Successfully got DNS record list from Cloudflare")
+        .debug_success("Successfully created new `cloudflare` client")
         .error()?;
 
-    let mut records = response
-        .result
-        .iter()
-        .filter(|record| matches!(record.content, DnsContent::A { .. }));
-
-    let record = if let Some(record) = records.next() {
-        if records.next().is_some() {
-            anyhow::bail!(
-                "multiple A records retrieved for {}, case is ambiguous",
-                cf_config.get_record_name()
-            )
+        Ok(Self { client })
+    }
+
+    /// Every record in `zone`, or only those called `name`, a page at a time
+    async fn list(&self, zone: &str, name: Option<&str>) -> Result<Vec<Record>> {
+        let mut records = Vec::new();
+        let mut page = 1;
+        loop {
+            let response = self
+                .client
+                .request(&ListRecords {
+                    zone,
+                    params: ListRecordsParams {
+                        name: name.map(str::to_string),
+                        page,
+                        per_page: PAGE_SIZE,
+                    },
+                })
+                .await
+                .context("failed to request DNS record list from Cloudflare")
+                .debug()
+                .debug_success("Successfully got DNS record list from Cloudflare")
+                .error()?;
+
+            records.extend(response.result.0.into_iter().map(Record::from));
+            let total_pages = response
+                .result_info
+                .as_ref()
+                .and_then(|info| info.get("total_pages"))
+                .and_then(|pages| pages.as_u64())
+                .unwrap_or(1);
+            if u64::from(page) >= total_pages {
+                break;
+            }
+            page += 1;
         }
 
-        record
-    } else {
-        anyhow::bail!(
-            "failed to find any A records for {}",
-            cf_config.get_record_name()
-        );
-    };
-
-    client
-        .request(&cf_config.get_update_request(record, ip))
-        .await
+        Ok(records)
+    }
+}
+
+impl DnsProvider for CloudflareProvider {
+    #[instrument(skip(self))]
+    async fn find_records(&self, zone: &str, name: &str) -> Result<Vec<Record>> {
+        self.list(zone, Some(name)).await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_records(&self, zone: &str) -> Result<Vec<Record>> {
+        self.list(zone, None).await
+    }
+
+    #[instrument(skip(self))]
+    async fn find_zone(&self, name: &str) -> Result<Option<String>> {
+        let response = self
+            .client
+            .request(&ListZones {
+                params: ListZonesParams {
+                    name: name.to_string(),
+                },
+            })
+            .await
+            .context("failed to request zone list from Cloudflare")
+            .debug()
+            .debug_success("Successfully got zone list from Cloudflare")
+            .error()?;
+
+        Ok(response
+            .result
+            .0
+            .into_iter()
+            .find(|zone| zone.name.eq_ignore_ascii_case(name))
+            .map(|zone| zone.id))
+    }
+
+    #[instrument(skip(self))]
+    async fn create_record(&self, zone: &str, params: &RecordParams) -> Result<Record> {
+        let response = self
+            .client
+            .request(&CreateRecord {
+                zone,
+                body: RecordBody::new(params)?,
+            })
+            .await
+            .context("failed to create DNS record on Cloudflare")
+            .debug()
+            .debug_success("Successfully created DNS record on Cloudflare")
+            .error()?;
+
+        Ok(response.result.into())
+    }
+
+    #[instrument(skip(self))]
+    async fn update_record(&self, zone: &str, id: &str, params: &RecordParams) -> Result<Record> {
+        let response = match self
+            .client
+            .request(&UpdateRecord {
+                zone,
+                id,
+                body: RecordBody::new(params)?,
+            })
+            .await
+        {
+            // Not an error worth logging, the caller looks the record up again
+            Err(ApiFailure::Error(status, _)) if status == reqwest::StatusCode::NOT_FOUND => {
+                return Err(RecordNotFound)
+                    .with_context(|| format!("no record `{id}` on Cloudflare"));
+            }
+            response => response,
+        }
         .context("failed to update DNS record on Cloudflare")
         .debug()
         .debug_success("Successfully updated DNS record on Cloudflare")
         .error()?;
 
-    if cache_config.get_persist() {
-        if let Some(State { last_sent_ip, .. }) = state {
-            *last_sent_ip = Some(ip);
-        } else {
-            *state = Some(State {
-                last_sent_ip: Some(ip),
-            });
+        Ok(response.result.into())
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_record(&self, zone: &str, id: &str) -> Result<()> {
+        self.client
+            .request(&DeleteDnsRecord {
+                zone_identifier: zone,
+                identifier: id,
+            })
+            .await
+            .context("failed to delete DNS record on Cloudflare")
+            .debug()
+            .debug_success("Successfully deleted DNS record on Cloudflare")
+            .error()?;
+
+        Ok(())
+    }
+
+    fn has_stable_ids(&self) -> bool {
+        true
+    }
+
+    fn can_list(&self) -> bool {
+        true
+    }
+}
+
+// The `cloudflare` crate's DNS endpoints can't read record types it doesn't
+// know about, and have no notion of record comments, so these replace them
+
+/// A DNS record as returned by the Cloudflare API
+#[derive(Debug, Deserialize)]
+struct DnsRecord {
+    id: String,
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    content: String,
+    ttl: u32,
+    proxied: Option<bool>,
+    comment: Option<String>,
+    modified_on: Option<String>,
+}
+
+impl ApiResult for DnsRecord {}
+
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+struct DnsRecords(Vec<DnsRecord>);
+
+impl ApiResult for DnsRecords {}
+
+impl From<DnsRecord> for Record {
+    fn from(value: DnsRecord) -> Self {
+        let content = match value.kind.as_str() {
+            "A" => value.content.parse().ok().map(RecordContent::A),
+            "AAAA" => value.content.parse().ok().map(RecordContent::Aaaa),
+            _ => None,
         }
+        .unwrap_or(RecordContent::Other {
+            kind: value.kind,
+            content: value.content,
+        });
+
+        Self {
+            id: value.id,
+            name: value.name,
+            content,
+            ttl: value.ttl,
+            proxied: value.proxied,
+            comment: value.comment,
+            modified: value.modified_on,
+        }
+    }
+}
+
+/// The fields of a record that are written, anything `None` is left as is
+#[derive(Debug, Serialize)]
+struct RecordBody<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<SvcbData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxied: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<&'a str>,
+}
+
+impl<'a> RecordBody<'a> {
+    fn new(params: &'a RecordParams) -> Result<Self> {
+        let kind = params.content.kind();
+        let (content, data) = match kind {
+            "A" | "AAAA" | "CNAME" | "NS" | "TXT" | "SRV" => {
+                (Some(params.content.to_string()), None)
+            }
+            "HTTPS" | "SVCB" => (None, Some(SvcbData::parse(&params.content.to_string())?)),
+            _ => anyhow::bail!("`{kind}` records are not supported by the Cloudflare provider"),
+        };
+
+        Ok(Self {
+            kind,
+            name: &params.name,
+            content,
+            data,
+            ttl: params.ttl,
+            proxied: params.proxied,
+            comment: params.comment.as_deref(),
+        })
+    }
+
+    fn to_json(&self) -> RequestBody<'_> {
+        RequestBody::Json(serde_json::to_string(self).expect("failed to serialize DNS record"))
     }
+}
 
-    Ok(())
+/// HTTPS and SVCB records are written as their parts rather than as `content`
+#[derive(Debug, Serialize)]
+struct SvcbData {
+    priority: u16,
+    target: String,
+    value: String,
+}
+
+impl SvcbData {
+    /// Parses presentation format, eg. `1 . alpn="h2" ipv4hint="192.0.2.1"`
+    fn parse(content: &str) -> Result<Self> {
+        let mut parts = content.trim().splitn(3, char::is_whitespace);
+        let priority = parts.next().unwrap_or_default();
+        let priority = priority
+            .parse()
+            .with_context(|| format!("`{priority}` is not a valid SVCB priority"))?;
+        let target = parts
+            .next()
+            .context("SVCB record has no target, use `.` for the owner name")?
+            .to_string();
+        let value = parts.next().unwrap_or_default().trim().to_string();
+        Ok(Self {
+            priority,
+            target,
+            value,
+        })
+    }
+}
+
+/// Just enough of a zone to know its ID, the crate's `Zone` insists on
+/// fields an API token scoped to DNS edits may not get to see
+#[derive(Debug, Deserialize)]
+struct ZoneSummary {
+    id: String,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+struct ZoneSummaries(Vec<ZoneSummary>);
+
+impl ApiResult for ZoneSummaries {}
+
+#[derive(Debug, Serialize)]
+struct ListZonesParams {
+    name: String,
+}
+
+/// `GET zones`
+#[derive(Debug)]
+struct ListZones {
+    params: ListZonesParams,
+}
+
+impl EndpointSpec for ListZones {
+    type JsonResponse = ZoneSummaries;
+    type ResponseType = ApiSuccess<Self::JsonResponse>;
+
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn path(&self) -> String {
+        "zones".to_string()
+    }
+
+    fn query(&self) -> Option<String> {
+        serialize_query(&self.params)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ListRecordsParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    page: u32,
+    per_page: u32,
+}
+
+/// `GET zones/:zone/dns_records`
+#[derive(Debug)]
+struct ListRecords<'a> {
+    zone: &'a str,
+    params: ListRecordsParams,
+}
+
+impl EndpointSpec for ListRecords<'_> {
+    type JsonResponse = DnsRecords;
+    type ResponseType = ApiSuccess<Self::JsonResponse>;
+
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn path(&self) -> String {
+        format!("zones/{}/dns_records", self.zone)
+    }
+
+    fn query(&self) -> Option<String> {
+        serialize_query(&self.params)
+    }
+}
+
+/// `POST zones/:zone/dns_records`
+#[derive(Debug)]
+struct CreateRecord<'a> {
+    zone: &'a str,
+    body: RecordBody<'a>,
+}
+
+impl EndpointSpec for CreateRecord<'_> {
+    type JsonResponse = DnsRecord;
+    type ResponseType = ApiSuccess<Self::JsonResponse>;
+
+    fn method(&self) -> Method {
+        Method::POST
+    }
+
+    fn path(&self) -> String {
+        format!("zones/{}/dns_records", self.zone)
+    }
+
+    fn body(&self) -> Option<RequestBody<'_>> {
+        Some(self.body.to_json())
+    }
+}
+
+/// `PATCH zones/:zone/dns_records/:id`, unlike `PUT` this leaves anything
+/// not in the body (eg. the comment) untouched
+#[derive(Debug)]
+struct UpdateRecord<'a> {
+    zone: &'a str,
+    id: &'a str,
+    body: RecordBody<'a>,
+}
+
+impl EndpointSpec for UpdateRecord<'_> {
+    type JsonResponse = DnsRecord;
+    type ResponseType = ApiSuccess<Self::JsonResponse>;
+
+    fn method(&self) -> Method {
+        Method::PATCH
+    }
+
+    fn path(&self) -> String {
+        format!("zones/{}/dns_records/{}", self.zone, self.id)
+    }
+
+    fn body(&self) -> Option<RequestBody<'_>> {
+        Some(self.body.to_json())
+    }
 }
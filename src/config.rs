@@ -1,16 +1,19 @@
-use std::net::Ipv4Addr;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 use anyhow::Context;
-use cloudflare::{
-    endpoints::dns::dns::{
-        DnsContent, DnsRecord, ListDnsRecords, ListDnsRecordsParams, UpdateDnsRecord,
-        UpdateDnsRecordParams,
-    },
-    framework::auth::Credentials,
-};
+use base64::Engine;
+use cloudflare::framework::auth::Credentials;
 use serde::{Deserialize, Serialize};
 
-use crate::anyhow_tracing::Tracing;
+use crate::{
+    anyhow_tracing::Tracing,
+    ip_find::{Cidr, IpVersion},
+    provider::ProviderKind,
+};
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Config {
@@ -20,13 +23,53 @@ pub struct Config {
     cloudflare: CloudflareConfig,
     #[serde(default)]
     cache: CacheConfig,
+    #[serde(default)]
+    daemon: DaemonConfig,
+    #[serde(default)]
+    damping: DampingConfig,
+    #[serde(default)]
+    replace: ReplaceConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rfc2136: Option<Rfc2136Config>,
+    #[serde(default)]
+    records: Vec<RecordConfig>,
 }
 
 impl Config {
+    /// Parses `config.toml`, pinpointing the offending line, column and key on failure
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let config: Self =
+            toml::from_str(text).map_err(|err| anyhow::anyhow!(describe_toml_error(text, &err)))?;
+        for record in config.get_records() {
+            record
+                .validate()
+                .with_context(|| format!("invalid config for {record}"))?;
+        }
+        Ok(config)
+    }
+
     pub fn is_active(&self) -> bool {
         self.active
     }
 
+    pub fn get_ip_versions(&self) -> Vec<IpVersion> {
+        let mut versions = Vec::new();
+        for record in self.get_records() {
+            for version in record.get_versions() {
+                if !versions.contains(&version) {
+                    versions.push(version);
+                }
+            }
+        }
+        versions.sort_by_key(|version| matches!(version, IpVersion::V6));
+        versions
+    }
+
+    /// Every managed record, the legacy single `[cloudflare]` record first
+    pub(crate) fn get_records(&self) -> impl Iterator<Item = &RecordConfig> {
+        self.cloudflare.record.iter().chain(self.records.iter())
+    }
+
     pub(crate) fn get_ip_config(&self) -> &IpFindConfig {
         &self.ip_find
     }
@@ -38,18 +81,62 @@ impl Config {
     pub(crate) fn get_cache_config(&self) -> &CacheConfig {
         &self.cache
     }
+
+    pub fn get_daemon_config(&self) -> &DaemonConfig {
+        &self.daemon
+    }
+
+    pub(crate) fn get_damping_config(&self) -> &DampingConfig {
+        &self.damping
+    }
+
+    pub(crate) fn get_replace_config(&self) -> &ReplaceConfig {
+        &self.replace
+    }
+
+    pub(crate) fn get_rfc2136_config(&self) -> Option<&Rfc2136Config> {
+        self.rfc2136.as_ref()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub(crate) struct IpFindConfig {
-    finders: Vec<String>,
+    finders: Vec<FinderConfig>,
+    #[serde(rename = "finders-v6", default)]
+    finders_v6: Vec<FinderConfig>,
     retries: Option<u8>,
     timeout: Option<u8>,
+    #[serde(default)]
+    strategy: FinderStrategy,
+    #[serde(default, deserialize_with = "deserialize_quorum")]
+    quorum: Option<u8>,
+    stagger: Option<u64>,
+    #[serde(default)]
+    allow: Vec<Cidr>,
+    #[serde(default)]
+    deny: Vec<Cidr>,
+}
+
+/// A quorum below two would trust a single finder again, which is what the
+/// strategy is there to avoid
+fn deserialize_quorum<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u8>, D::Error> {
+    let quorum = u8::deserialize(deserializer)?;
+    if quorum < 2 {
+        return Err(serde::de::Error::custom(format!(
+            "`quorum` must be at least 2, got {quorum}"
+        )));
+    }
+    Ok(Some(quorum))
 }
 
 impl IpFindConfig {
-    pub(crate) fn iter(&self) -> std::slice::Iter<'_, String> {
-        self.finders.iter()
+    pub(crate) fn iter(&self, version: IpVersion) -> std::slice::Iter<'_, FinderConfig> {
+        match version {
+            IpVersion::V4 => self.finders.iter(),
+            IpVersion::V6 => self.finders_v6.iter(),
+        }
     }
 
     pub(crate) fn get_retries(&self) -> u8 {
@@ -65,16 +152,163 @@ impl IpFindConfig {
             .debug()
             .unwrap_or(1)
     }
+
+    pub(crate) fn get_strategy(&self) -> FinderStrategy {
+        self.strategy
+    }
+
+    pub(crate) fn get_quorum(&self) -> u8 {
+        self.quorum
+            .context("`ip-find` config key `quorum` is `None`, defaulting to 2")
+            .debug()
+            .unwrap_or(2)
+    }
+
+    /// Delay between starting each finder when racing them
+    pub(crate) fn get_stagger(&self) -> Duration {
+        Duration::from_millis(
+            self.stagger
+                .context("`ip-find` config key `stagger` is `None`, defaulting to 250")
+                .debug()
+                .unwrap_or(250),
+        )
+    }
+
+    /// CIDRs exempt from the bogon check
+    pub(crate) fn get_allow(&self) -> &[Cidr] {
+        &self.allow
+    }
+
+    /// CIDRs that must never be published
+    pub(crate) fn get_deny(&self) -> &[Cidr] {
+        &self.deny
+    }
+}
+
+/// A finder entry, either just its URL or a table describing the request
+/// and how to read the IP out of the response
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum FinderConfig {
+    Url(String),
+    Detailed(DetailedFinderConfig),
+}
+
+impl FinderConfig {
+    pub(crate) fn get_url(&self) -> &str {
+        match self {
+            Self::Url(url) => url,
+            Self::Detailed(finder) => &finder.url,
+        }
+    }
+
+    pub(crate) fn get_detailed(&self) -> Option<&DetailedFinderConfig> {
+        match self {
+            Self::Url(_) => None,
+            Self::Detailed(finder) => Some(finder),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct DetailedFinderConfig {
+    url: String,
+    #[serde(default)]
+    format: ResponseFormat,
+    pointer: Option<String>,
+    regex: Option<String>,
+    key: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(rename = "user-agent")]
+    user_agent: Option<String>,
+}
+
+impl DetailedFinderConfig {
+    pub(crate) fn get_format(&self) -> ResponseFormat {
+        self.format
+    }
+
+    /// The JSON pointer to the IP, for the `json` format
+    pub(crate) fn get_pointer(&self) -> Option<&str> {
+        self.pointer.as_deref()
+    }
+
+    /// The regex matching the IP, for the `regex` format
+    pub(crate) fn get_regex(&self) -> Option<&str> {
+        self.regex.as_deref()
+    }
+
+    /// The key of the IP line, for the `key-value` format
+    pub(crate) fn get_key(&self) -> &str {
+        self.key
+            .as_deref()
+            .context("finder config key `key` is `None`, defaulting to \"ip\"")
+            .debug()
+            .unwrap_or("ip")
+    }
+
+    pub(crate) fn get_headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    pub(crate) fn get_user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+}
+
+/// How to read the IP out of a finder's response body
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ResponseFormat {
+    /// The whole body is the IP
+    #[default]
+    Plain,
+    /// The IP is the string at `pointer` in a JSON body
+    Json,
+    /// The IP is the `ip` named group, first group or whole match of `regex`
+    Regex,
+    /// The IP is the value of the `key=value` line named by `key`
+    KeyValue,
+}
+
+/// How the finders are consulted to settle on a public IP
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FinderStrategy {
+    /// Try each finder in turn, trusting the first one that answers
+    #[default]
+    Ordered,
+    /// Start the finders in order, `stagger` apart, trusting whichever answers first
+    Race,
+    /// Ask every finder at once, only trusting an IP that `quorum` of them agree on
+    Quorum,
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub(crate) struct CloudflareConfig {
     #[serde(rename = "api-key")]
     api_key: String,
-    #[serde(rename = "zone-identifier")]
-    zone_id: String,
-    #[serde(rename = "dns-record-name")]
-    record_name: String,
+    #[serde(flatten, deserialize_with = "deserialize_legacy_record")]
+    record: Option<RecordConfig>,
+}
+
+/// The legacy single record is optional as a whole, but a `[cloudflare]` table
+/// with any of its keys must hold a valid record. A plain flattened `Option`
+/// would quietly turn a typo into no record at all
+fn deserialize_legacy_record<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<RecordConfig>, D::Error> {
+    let table = toml::Table::deserialize(deserializer)?;
+    if table.is_empty() {
+        return Ok(None);
+    }
+    toml::Value::Table(table)
+        .try_into()
+        .map(Some)
+        .map_err(|err: toml::de::Error| {
+            serde::de::Error::custom(format!("invalid legacy record: {}", err.message()))
+        })
 }
 
 impl CloudflareConfig {
@@ -83,49 +317,305 @@ impl CloudflareConfig {
             token: self.api_key.clone(),
         }
     }
+}
+
+impl std::fmt::Debug for CloudflareConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CloudflareConfig {{ REDACTED }}")
+    }
+}
 
-    pub(crate) fn get_list_request(&self) -> ListDnsRecords {
-        ListDnsRecords {
-            zone_identifier: &self.zone_id,
-            params: ListDnsRecordsParams {
-                name: Some(self.record_name.clone()),
-                ..Default::default()
-            },
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Rfc2136Config {
+    server: String,
+    #[serde(rename = "key-name")]
+    key_name: Option<String>,
+    #[serde(rename = "key-secret")]
+    key_secret: Option<String>,
+    #[serde(rename = "key-algorithm")]
+    key_algorithm: Option<String>,
+    timeout: Option<u8>,
+    ttl: Option<u32>,
+}
+
+impl Rfc2136Config {
+    /// Resolves `server`, which may be a bare address or host (defaulting to port 53)
+    pub(crate) async fn get_server(&self) -> anyhow::Result<SocketAddr> {
+        if let Ok(addr) = self.server.parse::<SocketAddr>() {
+            return Ok(addr);
+        }
+        if let Ok(ip) = self.server.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, 53));
         }
+
+        let host = if self.server.contains(':') {
+            self.server.clone()
+        } else {
+            format!("{}:53", self.server)
+        };
+        tokio::net::lookup_host(&host)
+            .await
+            .with_context(|| format!("failed to look up `{host}`"))?
+            .next()
+            .with_context(|| format!("`{host}` did not resolve to any address"))
     }
 
-    pub(crate) fn get_update_request<'a>(
-        &'a self,
-        record: &'a DnsRecord,
-        ip: Ipv4Addr,
-    ) -> UpdateDnsRecord<'a> {
-        UpdateDnsRecord {
-            zone_identifier: &self.zone_id,
-            identifier: &record.id,
-            params: UpdateDnsRecordParams {
-                ttl: Some(record.ttl),
-                proxied: Some(record.proxied),
-                name: &record.name,
-                content: DnsContent::A { content: ip },
-            },
+    /// The TSIG key name and decoded secret, if a key is configured
+    pub(crate) fn get_key(&self) -> anyhow::Result<Option<(&str, Vec<u8>)>> {
+        let (Some(name), Some(secret)) = (&self.key_name, &self.key_secret) else {
+            anyhow::ensure!(
+                self.key_name.is_none() && self.key_secret.is_none(),
+                "`rfc2136` config keys `key-name` and `key-secret` must be set together"
+            );
+            return Ok(None);
+        };
+
+        if let Some(algorithm) = &self.key_algorithm {
+            anyhow::ensure!(
+                algorithm
+                    .trim_end_matches('.')
+                    .eq_ignore_ascii_case("hmac-sha256"),
+                "unsupported TSIG algorithm `{algorithm}`, only `hmac-sha256` is supported"
+            );
         }
+
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(secret.trim())
+            .context("`rfc2136` config key `key-secret` is not valid base64")?;
+        Ok(Some((name, secret)))
+    }
+
+    pub(crate) fn get_timeout(&self) -> u8 {
+        self.timeout
+            .context("`rfc2136` config key `timeout` is `None`, defaulting to 5")
+            .debug()
+            .unwrap_or(5)
+    }
+
+    pub(crate) fn get_ttl(&self) -> u32 {
+        self.ttl
+            .context("`rfc2136` config key `ttl` is `None`, defaulting to 300")
+            .debug()
+            .unwrap_or(300)
+    }
+}
+
+impl std::fmt::Debug for Rfc2136Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rfc2136Config {{ server: {:?}, REDACTED }}", self.server)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub(crate) struct RecordConfig {
+    #[serde(rename = "zone-identifier")]
+    zone_id: Option<String>,
+    #[serde(rename = "zone-name")]
+    zone_name: Option<String>,
+    #[serde(rename = "dns-record-name")]
+    record_name: String,
+    #[serde(rename = "record-type", default)]
+    record_type: RecordType,
+    #[serde(default)]
+    provider: ProviderKind,
+    create: Option<bool>,
+    ttl: Option<u32>,
+    proxied: Option<bool>,
+    comment: Option<String>,
+    #[serde(default)]
+    duplicates: DuplicateStrategy,
+    #[serde(rename = "record-id")]
+    record_id: Option<String>,
+    #[serde(rename = "match-comment")]
+    match_comment: Option<String>,
+    template: Option<String>,
+}
+
+impl RecordConfig {
+    /// The provider's ID of the zone, looked up by name if `None`
+    pub(crate) fn get_zone_id(&self) -> Option<&str> {
+        self.zone_id.as_deref()
+    }
+
+    /// The name of the zone to look up, inferred from the record's name if `None`
+    pub(crate) fn get_zone_name(&self) -> Option<&str> {
+        self.zone_name.as_deref()
     }
 
     pub(crate) fn get_record_name(&self) -> &str {
         &self.record_name
     }
+
+    pub(crate) fn get_record_type(&self) -> RecordType {
+        self.record_type
+    }
+
+    pub(crate) fn get_provider(&self) -> ProviderKind {
+        self.provider
+    }
+
+    /// The content of a TXT, HTTPS or SVCB record, `{ipv4}` and `{ipv6}`
+    /// standing in for the addresses
+    pub(crate) fn get_template(&self) -> Option<&str> {
+        self.template.as_deref()
+    }
+
+    /// The IP versions the record depends on, for templated records those
+    /// the template uses
+    pub(crate) fn get_versions(&self) -> Vec<IpVersion> {
+        if !self.record_type.is_templated() {
+            return self.record_type.versions().to_vec();
+        }
+        let template = self.template.as_deref().unwrap_or_default();
+        [IpVersion::V4, IpVersion::V6]
+            .into_iter()
+            .filter(|version| template.contains(version.placeholder()))
+            .collect()
+    }
+
+    /// Checks the keys that only make sense together, so a record that could
+    /// never be written is reported up front instead of skipped
+    fn validate(&self) -> anyhow::Result<()> {
+        let kind = self.record_type;
+        if self.template.is_none() {
+            anyhow::ensure!(!kind.is_templated(), "{kind} records need a `template`");
+            return Ok(());
+        }
+        anyhow::ensure!(
+            kind.is_templated(),
+            "`template` only applies to TXT, HTTPS and SVCB records"
+        );
+        anyhow::ensure!(
+            !self.get_versions().is_empty(),
+            "`template` has no `{{ipv4}}` or `{{ipv6}}` to fill in"
+        );
+        Ok(())
+    }
+
+    pub(crate) fn manages(&self, ip: &IpAddr) -> bool {
+        self.get_versions()
+            .iter()
+            .any(|version| version.matches(ip))
+    }
+
+    /// Whether to create the record when it doesn't exist
+    pub(crate) fn get_create(&self) -> bool {
+        self.create
+            .context("record config key `create` is `None`, defaulting to false")
+            .debug()
+            .unwrap_or(false)
+    }
+
+    /// TTL to give the record, the existing one is preserved if `None`
+    pub(crate) fn get_ttl(&self) -> Option<u32> {
+        self.ttl
+    }
+
+    /// Whether the record is proxied, the existing setting is preserved if `None`
+    pub(crate) fn get_proxied(&self) -> Option<bool> {
+        self.proxied
+    }
+
+    /// Comment to stamp the record with, `{timestamp}` standing in for the
+    /// time of the update. The existing one is preserved if `None`
+    pub(crate) fn get_comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    pub(crate) fn get_duplicates(&self) -> DuplicateStrategy {
+        self.duplicates
+    }
+
+    /// The provider's ID of the record to update, for the `pin` strategy
+    pub(crate) fn get_record_id(&self) -> Option<&str> {
+        self.record_id.as_deref()
+    }
+
+    /// Text in the comment of the record to update, for the `comment` strategy
+    pub(crate) fn get_match_comment(&self) -> Option<&str> {
+        self.match_comment.as_deref()
+    }
 }
 
-impl std::fmt::Debug for CloudflareConfig {
+impl std::fmt::Display for RecordConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "CloudflareConfig {{ REDACTED }}")
+        write!(f, "{} ({})", self.record_name, self.record_type)
     }
 }
 
+/// Which records of `dns-record-name` are managed, either the address
+/// records or one rendered from a `template`
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecordType {
+    #[default]
+    A,
+    #[serde(rename = "AAAA")]
+    Aaaa,
+    #[serde(rename = "both")]
+    Both,
+    #[serde(rename = "TXT")]
+    Txt,
+    #[serde(rename = "HTTPS")]
+    Https,
+    #[serde(rename = "SVCB")]
+    Svcb,
+}
+
+impl RecordType {
+    /// The IP versions of address records, templated records have none
+    pub(crate) fn versions(&self) -> &'static [IpVersion] {
+        match self {
+            Self::A => &[IpVersion::V4],
+            Self::Aaaa => &[IpVersion::V6],
+            Self::Both => &[IpVersion::V4, IpVersion::V6],
+            Self::Txt | Self::Https | Self::Svcb => &[],
+        }
+    }
+
+    pub(crate) fn is_templated(&self) -> bool {
+        matches!(self, Self::Txt | Self::Https | Self::Svcb)
+    }
+}
+
+impl std::fmt::Display for RecordType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::A => write!(f, "A"),
+            Self::Aaaa => write!(f, "AAAA"),
+            Self::Both => write!(f, "A+AAAA"),
+            Self::Txt => write!(f, "TXT"),
+            Self::Https => write!(f, "HTTPS"),
+            Self::Svcb => write!(f, "SVCB"),
+        }
+    }
+}
+
+/// Which record to update when the name has several of the managed type,
+/// eg. for round-robin DNS
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DuplicateStrategy {
+    /// Refuse to pick one
+    #[default]
+    Fail,
+    /// Only ever update the record with ID `record-id`
+    Pin,
+    /// Update the record whose comment contains `match-comment`
+    Comment,
+    /// Update the record that holds the previously sent IP
+    Previous,
+    /// Delete all but one record, then update that
+    Collapse,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub(crate) struct CacheConfig {
     ignore: Option<bool>,
     persist: Option<bool>,
+    #[serde(rename = "verify-every")]
+    verify_every: Option<u32>,
+    #[serde(rename = "max-age")]
+    max_age: Option<u64>,
 }
 
 impl CacheConfig {
@@ -142,4 +632,310 @@ impl CacheConfig {
             .debug()
             .unwrap_or(true)
     }
+
+    /// Every how many runs the live records are checked for drift while the
+    /// IP matches the cache, never if `None`
+    pub(crate) fn get_verify_every(&self) -> Option<u32> {
+        self.verify_every.filter(|every| *every > 0)
+    }
+
+    /// How long the cached IP is trusted before the live records are checked
+    /// again, forever if `None`
+    pub(crate) fn get_max_age(&self) -> Option<Duration> {
+        self.max_age.map(Duration::from_secs)
+    }
+}
+
+/// How long a new IP must be seen before it is published, so short bounces
+/// between addresses don't each cause an update
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub(crate) struct DampingConfig {
+    checks: Option<u32>,
+    dwell: Option<u64>,
+}
+
+impl DampingConfig {
+    /// Consecutive checks a new IP must be seen in, no requirement if `None`
+    pub(crate) fn get_checks(&self) -> Option<u32> {
+        self.checks
+    }
+
+    /// Time since a new IP was first seen before it counts as stable, no
+    /// requirement if `None`
+    pub(crate) fn get_dwell(&self) -> Option<Duration> {
+        self.dwell.map(Duration::from_secs)
+    }
+}
+
+/// Rewriting the old IP wherever it appears in the managed records' zones
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub(crate) struct ReplaceConfig {
+    enabled: Option<bool>,
+    #[serde(rename = "dry-run")]
+    dry_run: Option<bool>,
+}
+
+impl ReplaceConfig {
+    pub(crate) fn get_enabled(&self) -> bool {
+        self.enabled
+            .context("`replace` config key `enabled` is `None`, defaulting to false")
+            .debug()
+            .unwrap_or(false)
+    }
+
+    /// Whether to only log what would be changed
+    pub(crate) fn get_dry_run(&self) -> bool {
+        self.dry_run
+            .context("`replace` config key `dry-run` is `None`, defaulting to false")
+            .debug()
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DaemonConfig {
+    #[serde(default, deserialize_with = "deserialize_interval")]
+    interval: Option<u64>,
+    jitter: Option<u64>,
+    netlink: Option<bool>,
+    interface: Option<String>,
+    debounce: Option<u64>,
+    cooldown: Option<u64>,
+}
+
+/// Anything shorter would hammer the finders and the provider API
+const MIN_INTERVAL: u64 = 30;
+
+fn deserialize_interval<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    let interval = u64::deserialize(deserializer)?;
+    if interval < MIN_INTERVAL {
+        return Err(serde::de::Error::custom(format!(
+            "`interval` must be at least {MIN_INTERVAL} seconds, got {interval}"
+        )));
+    }
+    Ok(Some(interval))
+}
+
+impl DaemonConfig {
+    /// Time between checks when running with `--daemon`
+    pub fn get_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.interval
+                .context("`daemon` config key `interval` is `None`, defaulting to 900")
+                .debug()
+                .unwrap_or(900),
+        )
+    }
+
+    /// Upper bound of the random delay added to every interval
+    pub fn get_jitter(&self) -> Duration {
+        Duration::from_secs(
+            self.jitter
+                .context("`daemon` config key `jitter` is `None`, defaulting to 30")
+                .debug()
+                .unwrap_or(30),
+        )
+    }
+
+    /// Whether to also check immediately when rtnetlink reports a network change
+    pub fn get_netlink(&self) -> bool {
+        self.netlink
+            .context("`daemon` config key `netlink` is `None`, defaulting to false")
+            .debug()
+            .unwrap_or(false)
+    }
+
+    /// The WAN interface to watch for changes, every interface if `None`
+    pub fn get_interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+
+    /// How long the network must be quiet after a change before checking
+    pub fn get_debounce(&self) -> Duration {
+        Duration::from_secs(
+            self.debounce
+                .context("`daemon` config key `debounce` is `None`, defaulting to 5")
+                .debug()
+                .unwrap_or(5),
+        )
+    }
+
+    /// Minimum time between checks triggered by network changes
+    pub fn get_cooldown(&self) -> Duration {
+        Duration::from_secs(
+            self.cooldown
+                .context("`daemon` config key `cooldown` is `None`, defaulting to 60")
+                .debug()
+                .unwrap_or(60),
+        )
+    }
+}
+
+fn describe_toml_error(text: &str, err: &toml::de::Error) -> String {
+    let Some(span) = err.span() else {
+        return err.message().to_string();
+    };
+
+    let start = span.start.min(text.len());
+    let before = &text[..start];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+
+    // Walk back from the error to the key it belongs to and the table that key lives in,
+    // missing fields point at the table they are missing from instead
+    let missing_field = err.message().starts_with("missing field");
+    let mut key = None;
+    let mut table = None;
+    for source_line in text[..text[start..]
+        .find('\n')
+        .map(|i| start + i)
+        .unwrap_or(text.len())]
+        .lines()
+        .rev()
+    {
+        let source_line = source_line.trim();
+        if source_line.starts_with('[') {
+            table = Some(source_line.trim_matches(|c| c == '[' || c == ']').trim());
+            break;
+        }
+        if key.is_none()
+            && !missing_field
+            && let Some((name, _)) = source_line.split_once('=')
+            && is_toml_key(name.trim())
+        {
+            key = Some(name.trim());
+        }
+    }
+
+    let key = match (table, key) {
+        (Some(table), Some(key)) => format!("`{table}.{key}`"),
+        (None, Some(key)) | (Some(key), None) => format!("`{key}`"),
+        (None, None) => "the document root".to_string(),
+    };
+    let snippet = text
+        .get(span)
+        .and_then(|snippet| snippet.trim().lines().next())
+        .unwrap_or_default();
+
+    format!(
+        "invalid config at line {line}, column {column} (in {key}, near `{snippet}`): {}",
+        err.message().trim().replace('\n', "; ")
+    )
+}
+
+fn is_toml_key(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '"' | '\''))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "active = true\n[ip-find]\nfinders = []\n";
+
+    #[test]
+    fn cloudflare_without_record_has_none() {
+        let config = Config::parse(&format!("{BASE}[cloudflare]\napi-key = \"key\"\n")).unwrap();
+        assert_eq!(config.get_records().count(), 0);
+    }
+
+    #[test]
+    fn legacy_record_is_read() {
+        let config = Config::parse(&format!(
+            "{BASE}[cloudflare]\napi-key = \"key\"\nzone-identifier = \"zone\"\ndns-record-name = \"www.example.com\"\nrecord-type = \"both\"\n"
+        ))
+        .unwrap();
+        assert_eq!(config.get_records().count(), 1);
+        assert_eq!(config.get_ip_versions(), vec![IpVersion::V4, IpVersion::V6]);
+    }
+
+    #[test]
+    fn invalid_legacy_record_is_an_error() {
+        for record in [
+            "dns-record-name = \"www.example.com\"\nrecord-type = \"aaaa\"",
+            "dns-record-name = \"www.example.com\"\nttl = \"300\"",
+            "dns-record-nmae = \"www.example.com\"",
+        ] {
+            let text = format!("{BASE}[cloudflare]\napi-key = \"key\"\n{record}\n");
+            let err = Config::parse(&text).expect_err(record);
+            assert!(err.to_string().contains("cloudflare"), "{err}");
+        }
+    }
+
+    #[test]
+    fn invalid_cidr_is_an_error() {
+        let err = Config::parse(
+            "active = true\n[ip-find]\nfinders = []\ndeny = [\"192.0.2.300\"]\n[cloudflare]\napi-key = \"key\"\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("192.0.2.300"), "{err}");
+    }
+
+    #[test]
+    fn template_must_fit_the_record_type() {
+        let record = "[[records]]\nzone-identifier = \"zone\"\ndns-record-name = \"example.com\"\n";
+        for (keys, expected) in [
+            ("record-type = \"TXT\"", "TXT records need a `template`"),
+            (
+                "record-type = \"HTTPS\"\ntemplate = \"1 . alpn=h2\"",
+                "has no `{ipv4}` or `{ipv6}`",
+            ),
+            (
+                "record-type = \"AAAA\"\ntemplate = \"{ipv6}\"",
+                "only applies to TXT, HTTPS and SVCB",
+            ),
+        ] {
+            let text = format!("{BASE}[cloudflare]\napi-key = \"key\"\n{record}{keys}\n");
+            let err = Config::parse(&text).expect_err(keys);
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        }
+
+        let text = format!(
+            "{BASE}[cloudflare]\napi-key = \"key\"\n{record}record-type = \"SVCB\"\ntemplate = \"1 . ipv6hint={{ipv6}}\"\n"
+        );
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(config.get_ip_versions(), vec![IpVersion::V6]);
+    }
+
+    #[test]
+    fn quorum_below_two_is_an_error() {
+        for quorum in [0, 1] {
+            let err = Config::parse(&format!(
+                "{BASE}strategy = \"quorum\"\nquorum = {quorum}\n[cloudflare]\napi-key = \"key\"\n"
+            ))
+            .unwrap_err();
+            assert!(err.to_string().contains("at least 2"), "{err}");
+        }
+        let config = Config::parse(&format!(
+            "{BASE}quorum = 3\n[cloudflare]\napi-key = \"key\"\n"
+        ))
+        .unwrap();
+        assert_eq!(config.get_ip_config().get_quorum(), 3);
+    }
+
+    #[test]
+    fn short_interval_is_an_error() {
+        let daemon = |interval: u64| {
+            Config::parse(&format!(
+                "{BASE}[cloudflare]\napi-key = \"key\"\n[daemon]\ninterval = {interval}\njitter = 0\n"
+            ))
+        };
+        let err = daemon(0).unwrap_err();
+        assert!(err.to_string().contains("at least 30 seconds"), "{err}");
+        assert_eq!(
+            daemon(30).unwrap().get_daemon_config().get_interval(),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn default_config_round_trips() {
+        let text = toml::to_string_pretty(&Config::default()).unwrap();
+        Config::parse(&text).unwrap();
+    }
 }
@@ -0,0 +1,188 @@
+use std::{net::IpAddr, time::Duration};
+
+use tracing::instrument;
+
+use crate::{
+    config::Config,
+    ip_find::IpVersion,
+    state::{Observation, State, unix_now},
+};
+
+/// Records that `ip` was found by this check and decides whether it has been
+/// stable long enough to publish. A first run (nothing sent yet for this IP
+/// version) and `force` skip the damping entirely
+#[instrument(skip(config, state))]
+pub fn is_settled(config: &Config, state: &mut Option<State>, ip: IpAddr, force: bool) -> bool {
+    let damping = config.get_damping_config();
+    let (checks, dwell) = (damping.get_checks(), damping.get_dwell());
+    let version = IpVersion::of(&ip);
+    if checks.is_none() && dwell.is_none() {
+        return true;
+    }
+    if force {
+        tracing::debug!("Forced, skipping damping");
+        reset(state, version);
+        return true;
+    }
+
+    let Some(state) = state else {
+        tracing::debug!("Nothing sent yet, skipping damping");
+        return true;
+    };
+    match version.cached(state) {
+        None => {
+            tracing::debug!("Nothing sent for {version} yet, skipping damping");
+            *pending(state, version) = None;
+            return true;
+        }
+        Some(last) if last == ip => {
+            *pending(state, version) = None;
+            return true;
+        }
+        Some(_) => {}
+    }
+
+    let now = unix_now();
+    let observation = pending(state, version);
+    let observation = match observation {
+        Some(observation) if observation.ip == ip => {
+            observation.seen += 1;
+            observation
+        }
+        _ => observation.insert(Observation {
+            ip,
+            first_seen: now,
+            seen: 1,
+        }),
+    };
+
+    let age = Duration::from_secs(now.saturating_sub(observation.first_seen));
+    let settled = checks.is_some_and(|checks| observation.seen >= checks)
+        || dwell.is_some_and(|dwell| age >= dwell);
+    if !settled {
+        tracing::info!(
+            "{ip} has been seen in {} consecutive check(s) over {}s, waiting for it to settle",
+            observation.seen,
+            age.as_secs()
+        );
+    }
+    settled
+}
+
+/// Forgets any pending IP for `version`, eg. after it bounced back to the
+/// published one
+pub fn reset(state: &mut Option<State>, version: IpVersion) {
+    if let Some(state) = state {
+        *pending(state, version) = None;
+    }
+}
+
+fn pending(state: &mut State, version: IpVersion) -> &mut Option<Observation> {
+    match version {
+        IpVersion::V4 => &mut state.pending_ip,
+        IpVersion::V6 => &mut state.pending_ipv6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(damping: &str) -> Config {
+        Config::parse(&format!(
+            "active = true\n[ip-find]\nfinders = []\n[cloudflare]\napi-key = \"\"\n[damping]\n{damping}\n"
+        ))
+        .unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    /// A state that last sent `192.0.2.1`
+    fn published() -> Option<State> {
+        Some(State {
+            last_sent_ip: Some("192.0.2.1".parse().unwrap()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn waits_for_enough_checks() {
+        let config = config("checks = 3");
+        let mut state = published();
+
+        assert!(!is_settled(&config, &mut state, ip("192.0.2.2"), false));
+        assert!(!is_settled(&config, &mut state, ip("192.0.2.2"), false));
+        assert_eq!(state.as_ref().unwrap().pending_ip.unwrap().seen, 2);
+
+        // Another new IP starts over
+        assert!(!is_settled(&config, &mut state, ip("192.0.2.3"), false));
+        assert!(!is_settled(&config, &mut state, ip("192.0.2.3"), false));
+        assert!(is_settled(&config, &mut state, ip("192.0.2.3"), false));
+    }
+
+    #[test]
+    fn waits_for_the_dwell_since_first_seen() {
+        let config = config("dwell = 600");
+        let mut state = published();
+
+        assert!(!is_settled(&config, &mut state, ip("192.0.2.2"), false));
+        let pending = state.as_mut().unwrap().pending_ip.as_mut().unwrap();
+        assert!(pending.first_seen.abs_diff(unix_now()) <= 1);
+
+        pending.first_seen = unix_now() - 599;
+        assert!(!is_settled(&config, &mut state, ip("192.0.2.2"), false));
+        state
+            .as_mut()
+            .unwrap()
+            .pending_ip
+            .as_mut()
+            .unwrap()
+            .first_seen = unix_now() - 600;
+        assert!(is_settled(&config, &mut state, ip("192.0.2.2"), false));
+    }
+
+    #[test]
+    fn bouncing_back_clears_the_pending_ip() {
+        let config = config("checks = 2");
+        let mut state = published();
+
+        assert!(!is_settled(&config, &mut state, ip("192.0.2.2"), false));
+        assert!(is_settled(&config, &mut state, ip("192.0.2.1"), false));
+        assert_eq!(state.as_ref().unwrap().pending_ip, None);
+
+        // The count starts over rather than carrying on from before the bounce
+        assert!(!is_settled(&config, &mut state, ip("192.0.2.2"), false));
+    }
+
+    #[test]
+    fn force_skips_damping() {
+        let config = config("checks = 5\ndwell = 600");
+        let mut state = published();
+
+        assert!(!is_settled(&config, &mut state, ip("192.0.2.2"), false));
+        assert!(is_settled(&config, &mut state, ip("192.0.2.2"), true));
+        assert_eq!(state.as_ref().unwrap().pending_ip, None);
+    }
+
+    #[test]
+    fn first_run_skips_damping() {
+        let config = config("checks = 5");
+
+        assert!(is_settled(&config, &mut None, ip("192.0.2.2"), false));
+
+        // Only the IPv4 has been sent, the IPv6 is new
+        let mut state = published();
+        assert!(is_settled(&config, &mut state, ip("2001:db8::1"), false));
+        assert_eq!(state.as_ref().unwrap().pending_ipv6, None);
+    }
+
+    #[test]
+    fn no_damping_without_a_requirement() {
+        let config = config("");
+        let mut state = published();
+        assert!(is_settled(&config, &mut state, ip("192.0.2.2"), false));
+        assert_eq!(state.as_ref().unwrap().pending_ip, None);
+    }
+}
@@ -0,0 +1,543 @@
+//! Minimal DNS wire format (RFC 1035) and transport, shared by the RFC 2136
+//! provider and the DNS based IP finders
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use tracing::instrument;
+
+use crate::anyhow_tracing::Tracing;
+
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const TYPE_SOA: u16 = 6;
+pub(crate) const TYPE_TXT: u16 = 16;
+pub(crate) const TYPE_AAAA: u16 = 28;
+pub(crate) const TYPE_TSIG: u16 = 250;
+
+pub(crate) const CLASS_IN: u16 = 1;
+pub(crate) const CLASS_CH: u16 = 3;
+pub(crate) const CLASS_NONE: u16 = 254;
+pub(crate) const CLASS_ANY: u16 = 255;
+
+pub(crate) const OPCODE_UPDATE: u8 = 5;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Question {
+    pub(crate) name: String,
+    pub(crate) qtype: u16,
+    pub(crate) class: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ResourceRecord {
+    pub(crate) name: String,
+    pub(crate) rtype: u16,
+    pub(crate) class: u16,
+    pub(crate) ttl: u32,
+    pub(crate) rdata: Vec<u8>,
+}
+
+impl ResourceRecord {
+    /// The character-strings of a TXT record, joined together
+    pub(crate) fn txt(&self) -> String {
+        let mut text = String::new();
+        let mut rest = self.rdata.as_slice();
+        while let Some((&len, tail)) = rest.split_first() {
+            let len = (len as usize).min(tail.len());
+            text.push_str(&String::from_utf8_lossy(&tail[..len]));
+            rest = &tail[len..];
+        }
+        text
+    }
+}
+
+/// A DNS message. In UPDATE messages the sections are reused as
+/// zone, prerequisite, update and additional respectively
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Message {
+    pub(crate) id: u16,
+    pub(crate) flags: u16,
+    pub(crate) questions: Vec<Question>,
+    pub(crate) answers: Vec<ResourceRecord>,
+    pub(crate) authority: Vec<ResourceRecord>,
+    pub(crate) additional: Vec<ResourceRecord>,
+}
+
+impl Message {
+    pub(crate) fn query(name: &str, qtype: u16, class: u16) -> Self {
+        Self {
+            id: random_id(),
+            flags: FLAG_RECURSION_DESIRED,
+            questions: vec![Question {
+                name: name.to_string(),
+                qtype,
+                class,
+            }],
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn update(zone: &str) -> Self {
+        Self {
+            id: random_id(),
+            flags: (OPCODE_UPDATE as u16) << 11,
+            questions: vec![Question {
+                name: zone.to_string(),
+                qtype: TYPE_SOA,
+                class: CLASS_IN,
+            }],
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn opcode(&self) -> u8 {
+        ((self.flags >> 11) & 0xF) as u8
+    }
+
+    pub(crate) fn rcode(&self) -> u8 {
+        (self.flags & 0xF) as u8
+    }
+
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.flags & FLAG_TRUNCATED != 0
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(512);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        for count in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authority.len(),
+            self.additional.len(),
+        ] {
+            buf.extend_from_slice(&(count as u16).to_be_bytes());
+        }
+        for question in &self.questions {
+            encode_name(&mut buf, &question.name);
+            buf.extend_from_slice(&question.qtype.to_be_bytes());
+            buf.extend_from_slice(&question.class.to_be_bytes());
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authority)
+            .chain(&self.additional)
+        {
+            encode_record(&mut buf, record);
+        }
+        buf
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Result<Self> {
+        Self::decode_with_offset(buf).map(|(message, _)| message)
+    }
+
+    /// Decodes `buf`, also returning the offset the last additional record
+    /// starts at, which is where a TSIG record lives in a signed message
+    pub(crate) fn decode_with_offset(buf: &[u8]) -> Result<(Self, Option<usize>)> {
+        let mut reader = Reader::new(buf);
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+        let mut message = Self {
+            id,
+            flags,
+            ..Default::default()
+        };
+        for _ in 0..counts[0] {
+            message.questions.push(Question {
+                name: reader.name()?,
+                qtype: reader.u16()?,
+                class: reader.u16()?,
+            });
+        }
+        let mut last_offset = None;
+        for (count, section) in [
+            (counts[1], &mut message.answers),
+            (counts[2], &mut message.authority),
+            (counts[3], &mut message.additional),
+        ] {
+            for _ in 0..count {
+                last_offset = Some(reader.pos);
+                section.push(reader.record()?);
+            }
+        }
+        if counts[3] == 0 {
+            last_offset = None;
+        }
+        Ok((message, last_offset))
+    }
+}
+
+pub(crate) fn rcode_name(rcode: u8) -> &'static str {
+    match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Appends `name` in uncompressed wire format, lowercased so it is also
+/// canonical for signing
+pub(crate) fn encode_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+    {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend(label.iter().map(u8::to_ascii_lowercase));
+    }
+    buf.push(0);
+}
+
+pub(crate) fn encode_record(buf: &mut Vec<u8>, record: &ResourceRecord) {
+    encode_name(buf, &record.name);
+    buf.extend_from_slice(&record.rtype.to_be_bytes());
+    buf.extend_from_slice(&record.class.to_be_bytes());
+    buf.extend_from_slice(&record.ttl.to_be_bytes());
+    buf.extend_from_slice(&(record.rdata.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&record.rdata);
+}
+
+/// Encodes `text` as TXT rdata, split into 255 byte character-strings
+pub(crate) fn encode_txt(text: &str) -> Vec<u8> {
+    let mut rdata = Vec::with_capacity(text.len() + 1);
+    for chunk in text.as_bytes().chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend_from_slice(chunk);
+    }
+    if text.is_empty() {
+        rdata.push(0);
+    }
+    rdata
+}
+
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .context("DNS message ended unexpectedly")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn name(&mut self) -> Result<String> {
+        let (name, end) = read_name(self.buf, self.pos)?;
+        self.pos = end;
+        Ok(name)
+    }
+
+    fn record(&mut self) -> Result<ResourceRecord> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let rdata = self.take(len)?.to_vec();
+        Ok(ResourceRecord {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        })
+    }
+}
+
+/// Reads a possibly compressed name at `pos`, returning it and the
+/// position just after it
+fn read_name(buf: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Every pointer must go strictly backwards, which rules out loops
+    let mut limit = pos;
+    loop {
+        let len = *buf.get(pos).context("DNS name ended unexpectedly")?;
+        match len {
+            0 => {
+                pos += 1;
+                break;
+            }
+            len if len & 0xC0 == 0xC0 => {
+                let low = *buf.get(pos + 1).context("DNS name ended unexpectedly")?;
+                let target = (((len & 0x3F) as usize) << 8) | low as usize;
+                anyhow::ensure!(target < limit, "invalid DNS name compression pointer");
+                end.get_or_insert(pos + 2);
+                limit = target;
+                pos = target;
+            }
+            len => {
+                let label = buf
+                    .get(pos + 1..pos + 1 + len as usize)
+                    .context("DNS label ended unexpectedly")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len as usize;
+            }
+        }
+    }
+    Ok((labels.join("."), end.unwrap_or(pos)))
+}
+
+fn random_id() -> u16 {
+    let mut id = [0; 2];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut id)
+        .expect("failed to generate random DNS message id");
+    u16::from_be_bytes(id)
+}
+
+/// Sends `request` to `server` over UDP, retrying over TCP when the answer
+/// is truncated, and returns the raw response
+#[instrument(skip(request))]
+pub(crate) async fn exchange(
+    server: SocketAddr,
+    request: &[u8],
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    let response = tokio::time::timeout(timeout, exchange_udp(server, request))
+        .await
+        .context("timed out waiting for UDP DNS response")
+        .debug()?
+        .context("failed UDP DNS exchange")
+        .debug()?;
+
+    if Message::decode(&response).is_ok_and(|message| message.is_truncated()) {
+        tracing::debug!("UDP response from {server} was truncated, retrying over TCP");
+        return tokio::time::timeout(timeout, exchange_tcp(server, request))
+            .await
+            .context("timed out waiting for TCP DNS response")
+            .debug()?
+            .context("failed TCP DNS exchange")
+            .debug();
+    }
+
+    Ok(response)
+}
+
+async fn exchange_udp(server: SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+    let bind: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind)
+        .await
+        .context("failed to bind UDP socket")?;
+    socket
+        .connect(server)
+        .await
+        .with_context(|| format!("failed to connect UDP socket to {server}"))?;
+    socket
+        .send(request)
+        .await
+        .context("failed to send DNS request")?;
+
+    let mut buf = vec![0; 4096];
+    loop {
+        let len = socket
+            .recv(&mut buf)
+            .await
+            .context("failed to receive DNS response")?;
+        // Ignore stray datagrams that don't answer our message id
+        if len >= 2 && buf[..2] == request[..2] && buf[2] & 0x80 != 0 {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+async fn exchange_tcp(server: SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(server)
+        .await
+        .with_context(|| format!("failed to connect to {server} over TCP"))?;
+    let mut framed = Vec::with_capacity(request.len() + 2);
+    framed.extend_from_slice(&(request.len() as u16).to_be_bytes());
+    framed.extend_from_slice(request);
+    stream
+        .write_all(&framed)
+        .await
+        .context("failed to send DNS request")?;
+
+    let len = stream
+        .read_u16()
+        .await
+        .context("failed to read DNS response length")?;
+    let mut buf = vec![0; len as usize];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .context("failed to read DNS response")?;
+    Ok(buf)
+}
+
+/// Checks `response` is a reply to `request`
+pub(crate) fn check_response(request: &Message, response: &Message) -> Result<()> {
+    anyhow::ensure!(
+        response.id == request.id && response.flags & FLAG_RESPONSE != 0,
+        "DNS response does not match request"
+    );
+    anyhow::ensure!(
+        response.opcode() == request.opcode(),
+        "DNS response has unexpected opcode {}",
+        response.opcode()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, rtype: u16, class: u16, rdata: &[u8]) -> ResourceRecord {
+        ResourceRecord {
+            name: name.to_string(),
+            rtype,
+            class,
+            ttl: 300,
+            rdata: rdata.to_vec(),
+        }
+    }
+
+    #[test]
+    fn message_round_trips() {
+        let mut message = Message::update("example.com");
+        message
+            .answers
+            .push(record("a.example.com", TYPE_A, CLASS_IN, &[192, 0, 2, 1]));
+        message.authority.push(record(
+            "a.example.com",
+            TYPE_TXT,
+            CLASS_NONE,
+            &encode_txt("v=spf1"),
+        ));
+        message
+            .additional
+            .push(record("key", TYPE_TSIG, CLASS_ANY, &[1, 2, 3]));
+
+        let raw = message.encode();
+        let (decoded, tsig_offset) = Message::decode_with_offset(&raw).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.opcode(), OPCODE_UPDATE);
+
+        // The TSIG record is the last thing in the message
+        let mut tail = Vec::new();
+        encode_record(&mut tail, &message.additional[0]);
+        assert_eq!(tsig_offset, Some(raw.len() - tail.len()));
+        assert_eq!(
+            Message::decode_with_offset(&Message::query("a", TYPE_A, CLASS_IN).encode())
+                .unwrap()
+                .1,
+            None
+        );
+    }
+
+    #[test]
+    fn names_are_lowercased_and_trimmed() {
+        let mut buf = Vec::new();
+        encode_name(&mut buf, "WWW.Example.com.");
+        assert_eq!(buf, b"\x03www\x07example\x03com\x00");
+        assert_eq!(
+            read_name(&buf, 0).unwrap(),
+            ("www.example.com".to_string(), buf.len())
+        );
+    }
+
+    #[test]
+    fn txt_round_trips_across_character_strings() {
+        let text = "x".repeat(300);
+        let rdata = encode_txt(&text);
+        assert_eq!((rdata[0], rdata[256]), (255, 45));
+        assert_eq!(record("a", TYPE_TXT, CLASS_IN, &rdata).txt(), text);
+        assert_eq!(encode_txt(""), [0]);
+    }
+
+    #[test]
+    fn follows_compression_pointers() {
+        // "example.com" at 0, then "www" pointing back to it
+        let mut buf = b"\x07example\x03com\x00".to_vec();
+        let www = buf.len();
+        buf.extend_from_slice(b"\x03www\xC0\x00");
+        assert_eq!(
+            read_name(&buf, www).unwrap(),
+            ("www.example.com".to_string(), buf.len())
+        );
+    }
+
+    #[test]
+    fn rejects_compression_loops() {
+        // A pointer to itself
+        assert!(read_name(b"\xC0\x00", 0).is_err());
+        // Two labels pointing at each other
+        let buf = b"\x01a\xC0\x04\x01b\xC0\x00";
+        assert!(read_name(buf, 0).is_err());
+        assert!(read_name(buf, 4).is_err());
+        // A pointer forwards, past the end
+        assert!(read_name(b"\xC0\x05", 0).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let raw = Message::query("example.com", TYPE_A, CLASS_IN).encode();
+        for len in [0, 5, 12, raw.len() - 1] {
+            assert!(Message::decode(&raw[..len]).is_err(), "decoded {len} bytes");
+        }
+        let mut message = Message::default();
+        message
+            .answers
+            .push(record("a", TYPE_A, CLASS_IN, &[192, 0, 2, 1]));
+        let raw = message.encode();
+        assert!(Message::decode(&raw[..raw.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn matches_responses_to_requests() {
+        let request = Message::query("example.com", TYPE_A, CLASS_IN);
+        let mut response = request.clone();
+        assert!(check_response(&request, &response).is_err());
+        response.flags |= FLAG_RESPONSE;
+        check_response(&request, &response).unwrap();
+        response.id = response.id.wrapping_add(1);
+        assert!(check_response(&request, &response).is_err());
+    }
+}
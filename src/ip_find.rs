@@ -1,95 +1,528 @@
-use std::{net::Ipv4Addr, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 
 use anyhow::Context;
 use reqwest::{Client, Url};
+use tokio::task::JoinSet;
 use tracing::instrument;
 
-use crate::{anyhow_tracing::Tracing, config::Config, state::State};
+use crate::{
+    anyhow_tracing::Tracing,
+    config::{Config, FinderConfig, FinderStrategy},
+    state::State,
+};
+
+mod dns;
+mod filter;
+mod http;
+mod interface;
+mod natpmp;
+mod stun;
+mod upnp;
+
+pub(crate) use filter::Cidr;
 
 pub enum IpResult {
-    Found(Ipv4Addr),
-    MatchesCache,
+    Found(IpAddr),
+    /// The IP found is the one last sent
+    MatchesCache(IpAddr),
     NotFound,
 }
 
-#[instrument(skip(config, state))]
-pub async fn find_public_ip(config: &Config, state: &Option<State>) -> IpResult {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    pub(crate) fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Self::V4,
+            IpAddr::V6(_) => Self::V6,
+        }
+    }
+
+    pub(crate) fn matches(&self, ip: &IpAddr) -> bool {
+        match self {
+            Self::V4 => ip.is_ipv4(),
+            Self::V6 => ip.is_ipv6(),
+        }
+    }
+
+    pub(crate) fn unspecified(&self) -> IpAddr {
+        match self {
+            Self::V4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            Self::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        }
+    }
+
+    /// What stands in for an IP of this version in record templates
+    pub(crate) fn placeholder(&self) -> &'static str {
+        match self {
+            Self::V4 => "{ipv4}",
+            Self::V6 => "{ipv6}",
+        }
+    }
+
+    /// The IP of this version that was last sent
+    pub fn cached(&self, state: &State) -> Option<IpAddr> {
+        match self {
+            Self::V4 => state.last_sent_ip.map(IpAddr::V4),
+            Self::V6 => state.last_sent_ipv6.map(IpAddr::V6),
+        }
+    }
+}
+
+impl Display for IpVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::V4 => write!(f, "IPv4"),
+            Self::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// HTTP clients for the finders, kept around so a long running service can
+/// reuse their connection pools between checks
+#[derive(Debug)]
+pub struct FinderClients {
+    v4: Client,
+    v6: Client,
+}
+
+impl FinderClients {
+    #[instrument]
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            v4: Self::build(IpVersion::V4)?,
+            v6: Self::build(IpVersion::V6)?,
+        })
+    }
+
+    fn build(version: IpVersion) -> anyhow::Result<Client> {
+        // Binding to the unspecified address of the requested family forces the
+        // finder connection over IPv4 or IPv6, so dual-stack echo services report
+        // the address we actually asked for
+        Client::builder()
+            .local_address(version.unspecified())
+            .build()
+            .with_context(|| format!("failed to build {version} HTTP client"))
+            .error()
+    }
+
+    fn get(&self, version: IpVersion) -> &Client {
+        match version {
+            IpVersion::V4 => &self.v4,
+            IpVersion::V6 => &self.v6,
+        }
+    }
+}
+
+#[instrument(skip(config, clients, state))]
+pub async fn find_public_ip(
+    config: &Config,
+    clients: &FinderClients,
+    state: &Option<State>,
+    version: IpVersion,
+) -> IpResult {
     tracing::trace!("Finding public IP");
     let ip_config = config.get_ip_config();
     let retries = ip_config.get_retries();
     tracing::debug!("Using {retries} retries");
     let timeout = Duration::from_secs(ip_config.get_timeout() as u64);
     tracing::debug!("Using {timeout:?} timeout");
-    let client = Client::new();
+    let context = FinderContext {
+        client: clients.get(version).clone(),
+        retries,
+        timeout,
+        version,
+        filter: filter::IpFilter::new(ip_config.get_allow(), ip_config.get_deny()),
+    };
 
     let cache_config = config.get_cache_config();
     let ignore_cache = cache_config.get_ignore();
 
-    for url in ip_config.iter().filter_map(|try_url| {
-        Url::parse(try_url)
-            .with_context(|| format!("failed to parse url from `{try_url}`"))
-            .error()
-            .ok()
-    }) {
-        tracing::debug!("Trying {url}");
-        if let Some(ip) = try_url(&client, &url, retries, timeout).await {
-            if ignore_cache {
-                return IpResult::Found(ip);
-            } else if let Some(State {
-                last_sent_ip: Some(cached_ip),
-            }) = state
-            {
-                if cached_ip == &ip {
-                    return IpResult::MatchesCache;
-                } else {
-                    return IpResult::Found(ip);
-                }
-            } else {
-                return IpResult::Found(ip);
-            }
+    let finders: Vec<Finder> = ip_config
+        .iter(version)
+        .filter_map(|source| {
+            Finder::parse(source)
+                .with_context(|| format!("failed to parse finder from `{}`", source.get_url()))
+                .error()
+                .ok()
+        })
+        .collect();
+
+    let found = match ip_config.get_strategy() {
+        FinderStrategy::Ordered => find_ordered(&context, &finders).await,
+        FinderStrategy::Race => {
+            let stagger = ip_config.get_stagger();
+            tracing::debug!("Using {stagger:?} stagger");
+            find_race(&context, &finders, stagger).await
+        }
+        FinderStrategy::Quorum => {
+            let quorum = ip_config.get_quorum() as usize;
+            tracing::debug!("Using a quorum of {quorum}");
+            find_quorum(&context, &finders, quorum).await
+        }
+    };
+
+    let Some(ip) = found else {
+        tracing::error!("Failed to find public {version} address.");
+        return IpResult::NotFound;
+    };
+
+    if ignore_cache {
+        IpResult::Found(ip)
+    } else if let Some(cached_ip) = state.as_ref().and_then(|state| version.cached(state)) {
+        if cached_ip == ip {
+            IpResult::MatchesCache(ip)
         } else {
-            tracing::warn!("Finder `{url}` failed, trying next finder");
-        }
-    }
-
-    tracing::error!("No more finders to try. Failed to find public IPv4 address.");
-    IpResult::NotFound
-}
-
-#[instrument(skip(client, url, retries, timeout))]
-async fn try_url(client: &Client, url: &Url, retries: u8, timeout: Duration) -> Option<Ipv4Addr> {
-    tracing::trace!("Trying a URL");
-    for attempt in 0..=retries {
-        let final_attempt = attempt == retries;
-        if let Ok(response) = client
-            .get(url.clone())
-            .timeout(timeout)
-            .send()
-            .await
-            .with_context(|| format!("failed on attempt {} for `{url}`", attempt + 1))
-            .warn_or_error(final_attempt)
-        {
-            tracing::debug!("Got a Response from {url} on attempt {}", attempt + 1);
-            if let Ok(response) = response
-                .error_for_status()
-                .context("server responded with an error")
-                .warn_or_error(final_attempt)
-            {
-                tracing::debug!("Server responded with success");
-                if let Ok(text) = response
-                    .text()
+            IpResult::Found(ip)
+        }
+    } else {
+        IpResult::Found(ip)
+    }
+}
+
+/// Everything a finder needs to make its attempts
+#[derive(Debug, Clone)]
+struct FinderContext {
+    client: Client,
+    retries: u8,
+    timeout: Duration,
+    version: IpVersion,
+    filter: filter::IpFilter,
+}
+
+/// A single source of the public IP, parsed from an `ip-find` finder entry
+#[derive(Debug, Clone)]
+enum Finder {
+    Http(http::HttpFinder),
+    Stun(String),
+    Dns(dns::DnsFinder),
+    /// UPnP IGD, with the router's description URL if it shouldn't be discovered
+    Upnp(Option<Url>),
+    /// NAT-PMP or PCP, with the gateway if it isn't the default one
+    Gateway(natpmp::Protocol, Option<String>),
+    Interface(interface::InterfaceFinder),
+}
+
+impl Finder {
+    fn parse(config: &FinderConfig) -> anyhow::Result<Self> {
+        let source = config.get_url();
+        let url = Url::parse(source).ok();
+        if let Some(url) = url.filter(|url| matches!(url.scheme(), "http" | "https")) {
+            return Ok(Self::Http(http::HttpFinder::parse(
+                url,
+                config.get_detailed(),
+            )?));
+        }
+        anyhow::ensure!(
+            config.get_detailed().is_none(),
+            "response formats, headers and user-agent only apply to HTTP finders"
+        );
+
+        if let Some(server) = source.strip_prefix("stun://") {
+            return Ok(Self::Stun(with_default_port(
+                server.trim_end_matches('/'),
+                stun::DEFAULT_PORT,
+            )));
+        }
+
+        if let Some(interface) = source.strip_prefix("interface://") {
+            return Ok(Self::Interface(interface::InterfaceFinder::parse(
+                interface,
+            )?));
+        }
+
+        if let Some(description) = source.strip_prefix("upnp://") {
+            if description.is_empty() {
+                return Ok(Self::Upnp(None));
+            }
+            return Ok(Self::Upnp(Some(Url::parse(&format!(
+                "http://{description}"
+            ))?)));
+        }
+
+        for (scheme, protocol) in [
+            ("natpmp://", natpmp::Protocol::NatPmp),
+            ("pcp://", natpmp::Protocol::Pcp),
+        ] {
+            if let Some(gateway) = source.strip_prefix(scheme) {
+                let gateway = gateway.trim_end_matches('/');
+                let gateway =
+                    (!gateway.is_empty()).then(|| with_default_port(gateway, natpmp::DEFAULT_PORT));
+                return Ok(Self::Gateway(protocol, gateway));
+            }
+        }
+
+        let url = Url::parse(source)?;
+        anyhow::ensure!(
+            url.scheme() == "dns",
+            "unsupported finder scheme `{}`",
+            url.scheme()
+        );
+        Ok(Self::Dns(dns::DnsFinder::parse(&url)?))
+    }
+
+    /// Asks the finder for the IP, rejecting it if it can't be our public one
+    async fn try_find(&self, context: &FinderContext) -> Option<IpAddr> {
+        let ip = self.query(context).await?;
+        if let Some(reason) = context.filter.reject(&ip) {
+            tracing::warn!("Finder `{self}` returned {ip}, rejecting it as {reason}");
+            return None;
+        }
+        Some(ip)
+    }
+
+    async fn query(&self, context: &FinderContext) -> Option<IpAddr> {
+        match self {
+            Self::Http(finder) => {
+                finder
+                    .try_find(
+                        &context.client,
+                        context.retries,
+                        context.timeout,
+                        context.version,
+                    )
+                    .await
+            }
+            Self::Stun(server) => {
+                stun::try_stun(server, context.retries, context.timeout, context.version).await
+            }
+            Self::Dns(finder) => {
+                finder
+                    .try_find(context.retries, context.timeout, context.version)
                     .await
-                    .context("failed to fetch text from successful response")
-                    .warn_or_error(final_attempt)
-                {
-                    let text = text.trim();
-                    tracing::debug!("Server responded with `{text}`");
-                    if let Ok(ip) = text.parse() {
-                        return Some(ip);
-                    }
-                }
             }
+            Self::Upnp(description) => {
+                upnp::try_upnp(
+                    &context.client,
+                    description.as_ref(),
+                    context.retries,
+                    context.timeout,
+                    context.version,
+                )
+                .await
+            }
+            Self::Gateway(protocol, gateway) => {
+                natpmp::try_gateway(
+                    *protocol,
+                    gateway.as_deref(),
+                    context.retries,
+                    context.timeout,
+                    context.version,
+                )
+                .await
+            }
+            Self::Interface(finder) => finder.try_find(context.version),
+        }
+    }
+}
+
+impl Display for Finder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(finder) => write!(f, "{finder}"),
+            Self::Stun(server) => write!(f, "stun://{server}"),
+            Self::Dns(finder) => write!(f, "{finder}"),
+            Self::Upnp(None) => write!(f, "upnp://"),
+            Self::Upnp(Some(description)) => write!(
+                f,
+                "upnp://{}",
+                description.as_str().trim_start_matches("http://")
+            ),
+            Self::Gateway(protocol, gateway) => {
+                let scheme = match protocol {
+                    natpmp::Protocol::NatPmp => "natpmp",
+                    natpmp::Protocol::Pcp => "pcp",
+                };
+                write!(f, "{scheme}://{}", gateway.as_deref().unwrap_or_default())
+            }
+            Self::Interface(finder) => write!(f, "{finder}"),
         }
     }
+}
+
+/// Appends the default `port` to `server` if it doesn't name one
+fn with_default_port(server: &str, port: u16) -> String {
+    if server.parse::<SocketAddr>().is_ok() {
+        server.to_string()
+    } else if server.parse::<Ipv6Addr>().is_ok() {
+        format!("[{server}]:{port}")
+    } else if server.ends_with(']') || !server.contains(':') {
+        format!("{server}:{port}")
+    } else {
+        server.to_string()
+    }
+}
+
+/// Tries each finder in turn, trusting the first one that answers
+async fn find_ordered(context: &FinderContext, finders: &[Finder]) -> Option<IpAddr> {
+    for finder in finders {
+        tracing::debug!("Trying {finder}");
+        if let Some(ip) = finder.try_find(context).await {
+            return Some(ip);
+        } else {
+            tracing::warn!("Finder `{finder}` failed, trying next finder");
+        }
+    }
+
+    tracing::error!("No more finders to try.");
     None
 }
+
+/// Starts the finders in order, `stagger` apart, trusting whichever answers
+/// first and cancelling the rest
+async fn find_race(
+    context: &FinderContext,
+    finders: &[Finder],
+    stagger: Duration,
+) -> Option<IpAddr> {
+    let mut tasks = JoinSet::new();
+    for (position, finder) in finders.iter().cloned().enumerate() {
+        let context = context.clone();
+        tasks.spawn(async move {
+            tokio::time::sleep(stagger * position as u32).await;
+            tracing::debug!("Trying {finder}");
+            let ip = finder.try_find(&context).await;
+            (finder, ip)
+        });
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok((finder, Some(ip))) => {
+                tracing::debug!("Finder `{finder}` won the race");
+                tasks.abort_all();
+                return Some(ip);
+            }
+            Ok((finder, None)) => tracing::warn!("Finder `{finder}` failed"),
+            Err(err) => tracing::warn!("Finder task failed: {err}"),
+        }
+    }
+
+    tracing::error!("Every finder in the race failed.");
+    None
+}
+
+/// Asks every finder at once, only trusting an IP once `quorum` of them agree
+async fn find_quorum(context: &FinderContext, finders: &[Finder], quorum: usize) -> Option<IpAddr> {
+    let finders = distinct(finders);
+    if finders.len() < quorum {
+        tracing::error!(
+            "Quorum of {quorum} can never be reached with only {} finders",
+            finders.len()
+        );
+        return None;
+    }
+
+    let mut tasks = JoinSet::new();
+    for finder in finders.iter().cloned() {
+        let context = context.clone();
+        tasks.spawn(async move {
+            let ip = finder.try_find(&context).await;
+            (finder, ip)
+        });
+    }
+
+    let mut votes: HashMap<IpAddr, Vec<Finder>> = HashMap::new();
+    let mut remaining = finders.len();
+    while let Some(result) = tasks.join_next().await {
+        remaining -= 1;
+        let (finder, ip) = match result {
+            Ok(answer) => answer,
+            Err(err) => {
+                tracing::warn!("Finder task failed: {err}");
+                continue;
+            }
+        };
+
+        let Some(ip) = ip else {
+            tracing::warn!("Finder `{finder}` failed");
+            continue;
+        };
+        tracing::debug!("Finder `{finder}` voted for {ip}");
+        let voters = votes.entry(ip).or_default();
+        voters.push(finder);
+        if voters.len() >= quorum {
+            report_disagreements(&votes, Some(ip));
+            return Some(ip);
+        }
+
+        // Stop early once no IP can reach the quorum with the votes left
+        let leader = votes.values().map(Vec::len).max().unwrap_or(0);
+        if leader + remaining < quorum {
+            break;
+        }
+    }
+
+    tracing::error!("Finders failed to reach a quorum of {quorum}");
+    report_disagreements(&votes, None);
+    None
+}
+
+/// `finders` without repeats, so a finder listed twice can't outvote the others
+fn distinct(finders: &[Finder]) -> Vec<Finder> {
+    let mut seen = Vec::new();
+    let mut distinct = Vec::new();
+    for finder in finders {
+        let name = finder.to_string();
+        if seen.contains(&name) {
+            tracing::warn!("Finder `{name}` is listed more than once, it only gets one vote");
+            continue;
+        }
+        seen.push(name);
+        distinct.push(finder.clone());
+    }
+    distinct
+}
+
+fn report_disagreements(votes: &HashMap<IpAddr, Vec<Finder>>, accepted: Option<IpAddr>) {
+    for (ip, voters) in votes {
+        if Some(*ip) != accepted {
+            let voters: Vec<String> = voters.iter().map(Finder::to_string).collect();
+            tracing::warn!(
+                "Finders disagree: {ip} reported by {} finder(s) [{}]",
+                voters.len(),
+                voters.join(", ")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finders(urls: &[&str]) -> Vec<Finder> {
+        urls.iter()
+            .map(|url| Finder::parse(&FinderConfig::Url(url.to_string())).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn repeated_finders_only_vote_once() {
+        let finders = finders(&[
+            "stun://stun.example.org",
+            "https://icanhazip.com/",
+            "stun://stun.example.org:3478",
+            "https://icanhazip.com/",
+            "dns://resolver1.opendns.com/myip.opendns.com",
+        ]);
+        let names: Vec<String> = distinct(&finders).iter().map(Finder::to_string).collect();
+        assert_eq!(
+            names,
+            [
+                "stun://stun.example.org:3478",
+                "https://icanhazip.com/",
+                "dns://resolver1.opendns.com:53/myip.opendns.com"
+            ]
+        );
+    }
+}
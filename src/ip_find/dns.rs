@@ -0,0 +1,335 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use reqwest::Url;
+use tracing::instrument;
+
+use super::IpVersion;
+use crate::{
+    anyhow_tracing::Tracing,
+    dns::{self, CLASS_CH, CLASS_IN, Message, TYPE_A, TYPE_AAAA, TYPE_TXT},
+};
+
+const DEFAULT_PORT: u16 = 53;
+
+/// Asks a resolver about a name that answers with the address the query came
+/// from, like `myip.opendns.com` or `whoami.cloudflare`
+#[derive(Debug, Clone)]
+pub(super) struct DnsFinder {
+    resolver: String,
+    name: String,
+    qtype: Option<u16>,
+    class: u16,
+    retries: Option<u8>,
+    timeout: Option<Duration>,
+}
+
+impl DnsFinder {
+    /// Parses `dns://resolver[:port]/name?type=A|AAAA|TXT&class=IN|CH`, with
+    /// optional `retries` and `timeout` (seconds) overriding the `ip-find` ones
+    pub(super) fn parse(url: &Url) -> Result<Self> {
+        let host = url.host_str().context("DNS finder has no resolver")?;
+        let resolver = format!("{host}:{}", url.port().unwrap_or(DEFAULT_PORT));
+
+        let name = url.path().trim_start_matches('/').trim_end_matches('.');
+        anyhow::ensure!(!name.is_empty(), "DNS finder has no name to query");
+
+        let mut finder = Self {
+            resolver,
+            name: name.to_string(),
+            qtype: None,
+            class: CLASS_IN,
+            retries: None,
+            timeout: None,
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "type" => {
+                    finder.qtype = Some(match value.to_ascii_uppercase().as_str() {
+                        "A" => TYPE_A,
+                        "AAAA" => TYPE_AAAA,
+                        "TXT" => TYPE_TXT,
+                        _ => anyhow::bail!("unsupported DNS finder type `{value}`"),
+                    })
+                }
+                "class" => {
+                    finder.class = match value.to_ascii_uppercase().as_str() {
+                        "IN" => CLASS_IN,
+                        "CH" => CLASS_CH,
+                        _ => anyhow::bail!("unsupported DNS finder class `{value}`"),
+                    }
+                }
+                "retries" => {
+                    finder.retries = Some(value.parse().with_context(|| {
+                        format!("DNS finder `retries` must be a number, got `{value}`")
+                    })?)
+                }
+                "timeout" => {
+                    finder.timeout =
+                        Some(Duration::from_secs(value.parse().with_context(|| {
+                            format!("DNS finder `timeout` must be a number, got `{value}`")
+                        })?))
+                }
+                _ => anyhow::bail!("unknown DNS finder option `{key}`"),
+            }
+        }
+
+        Ok(finder)
+    }
+
+    /// The record type to ask for, defaulting to the address record of `version`
+    fn qtype(&self, version: IpVersion) -> u16 {
+        self.qtype.unwrap_or(match version {
+            IpVersion::V4 => TYPE_A,
+            IpVersion::V6 => TYPE_AAAA,
+        })
+    }
+
+    #[instrument(skip(retries, timeout))]
+    pub(super) async fn try_find(
+        &self,
+        retries: u8,
+        timeout: Duration,
+        version: IpVersion,
+    ) -> Option<IpAddr> {
+        tracing::trace!("Trying a DNS finder");
+        let retries = self.retries.unwrap_or(retries);
+        let timeout = self.timeout.unwrap_or(timeout);
+        for attempt in 0..=retries {
+            let final_attempt = attempt == retries;
+            if let Ok(ips) = self
+                .query(timeout, version)
+                .await
+                .with_context(|| format!("failed on attempt {} for `{self}`", attempt + 1))
+                .warn_or_error(final_attempt)
+            {
+                tracing::debug!("Resolver answered with {ips:?}");
+                match ips.iter().find(|ip| version.matches(ip)) {
+                    Some(ip) => return Some(*ip),
+                    None => tracing::warn!("Resolver answered with {ips:?}, expected {version}"),
+                }
+            }
+        }
+        None
+    }
+
+    async fn query(&self, timeout: Duration, version: IpVersion) -> Result<Vec<IpAddr>> {
+        // The answer is the address the query arrived from, so the resolver
+        // has to be reached over the IP version we are looking for
+        let server = tokio::net::lookup_host(&self.resolver)
+            .await
+            .with_context(|| format!("failed to resolve `{}`", self.resolver))?
+            .find(|address| version.matches(&address.ip()))
+            .with_context(|| format!("`{}` has no {version} address", self.resolver))?;
+
+        let qtype = self.qtype(version);
+        let request = Message::query(&self.name, qtype, self.class);
+        let response = dns::exchange(server, &request.encode(), timeout).await?;
+        let response = Message::decode(&response).context("failed to decode DNS response")?;
+        dns::check_response(&request, &response)?;
+        anyhow::ensure!(
+            response.rcode() == 0,
+            "resolver responded with {}",
+            dns::rcode_name(response.rcode())
+        );
+
+        let ips: Vec<IpAddr> = response
+            .answers
+            .iter()
+            .filter(|record| record.rtype == qtype)
+            .filter_map(|record| match (record.rtype, record.rdata.as_slice()) {
+                (TYPE_A, &[a, b, c, d]) => Some(IpAddr::V4(Ipv4Addr::new(a, b, c, d))),
+                (TYPE_AAAA, rdata) => <[u8; 16]>::try_from(rdata)
+                    .ok()
+                    .map(|octets| IpAddr::V6(Ipv6Addr::from(octets))),
+                (TYPE_TXT, _) => record.txt().trim().parse().ok(),
+                _ => None,
+            })
+            .collect();
+        anyhow::ensure!(!ips.is_empty(), "resolver answered with no addresses");
+        Ok(ips)
+    }
+}
+
+impl Display for DnsFinder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "dns://{}/{}", self.resolver, self.name)?;
+        if let Some(qtype) = self.qtype {
+            let qtype = match qtype {
+                TYPE_A => "A",
+                TYPE_AAAA => "AAAA",
+                _ => "TXT",
+            };
+            write!(f, "?type={qtype}")?;
+        }
+        if self.class == CLASS_CH {
+            let separator = if self.qtype.is_some() { '&' } else { '?' };
+            write!(f, "{separator}class=CH")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::dns::ResourceRecord;
+
+    fn parse(url: &str) -> Result<DnsFinder> {
+        DnsFinder::parse(&Url::parse(url).unwrap())
+    }
+
+    fn answer(rtype: u16, rdata: &[u8]) -> ResourceRecord {
+        ResourceRecord {
+            name: "whoami.example".to_string(),
+            rtype,
+            class: CLASS_IN,
+            ttl: 0,
+            rdata: rdata.to_vec(),
+        }
+    }
+
+    /// Answers a single query with `answers` and `rcode`, after a stray
+    /// reply to some other message id
+    async fn responder(answers: Vec<ResourceRecord>, rcode: u16) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+            let request = Message::decode(&buf[..len]).unwrap();
+            let mut response = Message {
+                id: request.id,
+                flags: request.flags | 0x8000 | rcode,
+                questions: request.questions.clone(),
+                answers,
+                ..Default::default()
+            };
+            response.id = request.id.wrapping_add(1);
+            socket.send_to(&response.encode(), client).await.unwrap();
+            response.id = request.id;
+            socket.send_to(&response.encode(), client).await.unwrap();
+        });
+        format!("dns://{address}/whoami.example")
+    }
+
+    #[test]
+    fn parses_defaults() {
+        let finder = parse("dns://resolver.example/myip.example.").unwrap();
+        assert_eq!(finder.resolver, "resolver.example:53");
+        assert_eq!(finder.name, "myip.example");
+        assert_eq!(finder.qtype(IpVersion::V4), TYPE_A);
+        assert_eq!(finder.qtype(IpVersion::V6), TYPE_AAAA);
+        assert_eq!(finder.class, CLASS_IN);
+        assert_eq!(finder.retries, None);
+        assert_eq!(finder.timeout, None);
+        assert_eq!(finder.to_string(), "dns://resolver.example:53/myip.example");
+    }
+
+    #[test]
+    fn parses_options() {
+        let finder =
+            parse("dns://1.1.1.1:5353/whoami.cloudflare?type=txt&class=CH&retries=3&timeout=1")
+                .unwrap();
+        assert_eq!(finder.resolver, "1.1.1.1:5353");
+        assert_eq!(finder.qtype(IpVersion::V6), TYPE_TXT);
+        assert_eq!(finder.class, CLASS_CH);
+        assert_eq!(finder.retries, Some(3));
+        assert_eq!(finder.timeout, Some(Duration::from_secs(1)));
+        assert_eq!(
+            finder.to_string(),
+            "dns://1.1.1.1:5353/whoami.cloudflare?type=TXT&class=CH"
+        );
+
+        let finder = parse("dns://resolver.example/myip.example?type=AAAA").unwrap();
+        assert_eq!(finder.qtype(IpVersion::V4), TYPE_AAAA);
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        for url in [
+            "dns://resolver.example/",
+            "dns://resolver.example/myip.example?type=MX",
+            "dns://resolver.example/myip.example?class=HS",
+            "dns://resolver.example/myip.example?retries=many",
+            "dns://resolver.example/myip.example?retries=300",
+            "dns://resolver.example/myip.example?timeout=1.5",
+            "dns://resolver.example/myip.example?port=53",
+        ] {
+            assert!(parse(url).is_err(), "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn extracts_a_records() {
+        let url = responder(
+            vec![
+                answer(TYPE_TXT, &dns::encode_txt("192.0.2.1")),
+                answer(TYPE_A, &[203, 0, 113, 7]),
+            ],
+            0,
+        )
+        .await;
+        let finder = parse(&url).unwrap();
+        assert_eq!(
+            finder
+                .try_find(0, Duration::from_secs(2), IpVersion::V4)
+                .await,
+            Some("203.0.113.7".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn extracts_aaaa_records() {
+        let ip: Ipv6Addr = "2001:db8::7".parse().unwrap();
+        let url = responder(vec![answer(TYPE_AAAA, &ip.octets())], 0).await;
+        let finder = parse(&format!("{url}?type=AAAA")).unwrap();
+        assert_eq!(
+            finder
+                .query(Duration::from_secs(2), IpVersion::V4)
+                .await
+                .unwrap(),
+            [IpAddr::V6(ip)]
+        );
+    }
+
+    #[tokio::test]
+    async fn extracts_txt_records() {
+        let url = responder(vec![answer(TYPE_TXT, &dns::encode_txt(" 203.0.113.7 "))], 0).await;
+        let finder = parse(&format!("{url}?type=TXT&class=CH")).unwrap();
+        assert_eq!(
+            finder
+                .query(Duration::from_secs(2), IpVersion::V4)
+                .await
+                .unwrap(),
+            ["203.0.113.7".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_without_addresses() {
+        let url = responder(vec![answer(TYPE_TXT, &dns::encode_txt("hello"))], 0).await;
+        let finder = parse(&format!("{url}?type=TXT")).unwrap();
+        assert!(
+            finder
+                .query(Duration::from_secs(2), IpVersion::V4)
+                .await
+                .is_err()
+        );
+
+        // NXDOMAIN
+        let url = responder(vec![], 3).await;
+        let err = parse(&url)
+            .unwrap()
+            .query(Duration::from_secs(2), IpVersion::V4)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("NXDOMAIN"), "{err}");
+    }
+}
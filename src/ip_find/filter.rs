@@ -0,0 +1,239 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Ranges that can never be our public address, and why. IPv6 outside the
+/// global unicast `2000::/3` is rejected before these are checked
+const BOGONS: [(Cidr, &str); 19] = [
+    (Cidr::v4(Ipv4Addr::new(0, 0, 0, 0), 8), "\"this network\""),
+    (
+        Cidr::v4(Ipv4Addr::new(10, 0, 0, 0), 8),
+        "private (RFC 1918)",
+    ),
+    (
+        Cidr::v4(Ipv4Addr::new(100, 64, 0, 0), 10),
+        "CGNAT shared (RFC 6598)",
+    ),
+    (Cidr::v4(Ipv4Addr::new(127, 0, 0, 0), 8), "loopback"),
+    (Cidr::v4(Ipv4Addr::new(169, 254, 0, 0), 16), "link-local"),
+    (
+        Cidr::v4(Ipv4Addr::new(172, 16, 0, 0), 12),
+        "private (RFC 1918)",
+    ),
+    (
+        Cidr::v4(Ipv4Addr::new(192, 0, 0, 0), 24),
+        "IETF protocol assignment",
+    ),
+    (Cidr::v4(Ipv4Addr::new(192, 0, 2, 0), 24), "documentation"),
+    (
+        Cidr::v4(Ipv4Addr::new(192, 88, 99, 0), 24),
+        "6to4 relay anycast",
+    ),
+    (
+        Cidr::v4(Ipv4Addr::new(192, 168, 0, 0), 16),
+        "private (RFC 1918)",
+    ),
+    (Cidr::v4(Ipv4Addr::new(198, 18, 0, 0), 15), "benchmarking"),
+    (
+        Cidr::v4(Ipv4Addr::new(198, 51, 100, 0), 24),
+        "documentation",
+    ),
+    (Cidr::v4(Ipv4Addr::new(203, 0, 113, 0), 24), "documentation"),
+    (Cidr::v4(Ipv4Addr::new(224, 0, 0, 0), 4), "multicast"),
+    (Cidr::v4(Ipv4Addr::new(240, 0, 0, 0), 4), "reserved"),
+    (
+        Cidr::v6(Ipv6Addr::new(0x2001, 0x2, 0, 0, 0, 0, 0, 0), 48),
+        "benchmarking",
+    ),
+    (
+        Cidr::v6(Ipv6Addr::new(0x2001, 0x10, 0, 0, 0, 0, 0, 0), 28),
+        "ORCHID",
+    ),
+    (
+        Cidr::v6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0), 32),
+        "documentation",
+    ),
+    (
+        Cidr::v6(Ipv6Addr::new(0x3fff, 0, 0, 0, 0, 0, 0, 0), 20),
+        "documentation",
+    ),
+];
+
+/// An IP network in CIDR notation, a bare address being a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    const fn v4(network: Ipv4Addr, prefix: u8) -> Self {
+        Self {
+            network: IpAddr::V4(network),
+            prefix,
+        }
+    }
+
+    const fn v6(network: Ipv6Addr, prefix: u8) -> Self {
+        Self {
+            network: IpAddr::V6(network),
+            prefix,
+        }
+    }
+
+    fn parse(source: &str) -> Result<Self> {
+        let (network, prefix) = source.split_once('/').unwrap_or((source, ""));
+        let network: IpAddr = network
+            .trim()
+            .parse()
+            .with_context(|| format!("`{network}` is not an IP address"))?;
+        let max = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix.trim() {
+            "" => max,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .with_context(|| format!("`{prefix}` is not a prefix length from 0 to {max}"))?,
+        };
+        Ok(Self { network, prefix })
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        Self::parse(&value)
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(value: Cidr) -> Self {
+        value.to_string()
+    }
+}
+
+/// Decides whether a found IP may be published
+#[derive(Debug, Clone, Default)]
+pub(super) struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    /// The entries are parsed along with the config, so are known to be valid
+    pub(super) fn new(allow: &[Cidr], deny: &[Cidr]) -> Self {
+        Self {
+            allow: allow.to_vec(),
+            deny: deny.to_vec(),
+        }
+    }
+
+    /// Why `ip` must not be published, if it mustn't. `deny` beats `allow`,
+    /// which beats the built in bogon ranges
+    pub(super) fn reject(&self, ip: &IpAddr) -> Option<String> {
+        if let Some(cidr) = self.deny.iter().find(|cidr| cidr.contains(ip)) {
+            return Some(format!("denied by {cidr}"));
+        }
+        if self.allow.iter().any(|cidr| cidr.contains(ip)) {
+            return None;
+        }
+        bogon(ip).map(|reason| format!("a {reason} address"))
+    }
+}
+
+fn bogon(ip: &IpAddr) -> Option<&'static str> {
+    if let IpAddr::V6(ip) = ip
+        && ip.segments()[0] & 0xe000 != 0x2000
+    {
+        return Some("non global unicast");
+    }
+    BOGONS
+        .iter()
+        .find(|(cidr, _)| cidr.contains(ip))
+        .map(|(_, reason)| *reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(sources: &[&str]) -> Vec<Cidr> {
+        sources
+            .iter()
+            .map(|source| Cidr::parse(source).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(
+            Cidr::parse("192.0.2.1").unwrap().to_string(),
+            "192.0.2.1/32"
+        );
+        assert_eq!(
+            Cidr::parse("2001:db8::/32").unwrap().to_string(),
+            "2001:db8::/32"
+        );
+        assert!(Cidr::parse("192.0.2.0/33").is_err());
+        assert!(Cidr::parse("192.0.2/24").is_err());
+        assert!(Cidr::parse("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn contains_masks_the_prefix() {
+        let cidr = Cidr::parse("100.64.0.0/10").unwrap();
+        assert!(cidr.contains(&"100.127.255.255".parse().unwrap()));
+        assert!(!cidr.contains(&"100.128.0.0".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+        assert!(
+            Cidr::parse("0.0.0.0/0")
+                .unwrap()
+                .contains(&"203.0.113.9".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_bogons_unless_allowed() {
+        let filter = IpFilter::new(
+            &cidrs(&["100.64.0.0/10"]),
+            &cidrs(&["100.64.1.1", "8.8.8.8"]),
+        );
+        assert!(filter.reject(&"1.1.1.1".parse().unwrap()).is_none());
+        assert!(filter.reject(&"192.168.1.1".parse().unwrap()).is_some());
+        assert!(filter.reject(&"fe80::1".parse().unwrap()).is_some());
+        assert!(filter.reject(&"100.64.0.1".parse().unwrap()).is_none());
+        // deny beats allow
+        assert!(filter.reject(&"100.64.1.1".parse().unwrap()).is_some());
+        assert!(filter.reject(&"8.8.8.8".parse().unwrap()).is_some());
+    }
+}
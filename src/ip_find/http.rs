@@ -0,0 +1,181 @@
+use std::{fmt::Display, net::IpAddr, time::Duration};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use reqwest::{
+    Client, Url,
+    header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT},
+};
+use tracing::instrument;
+
+use super::IpVersion;
+use crate::{
+    anyhow_tracing::Tracing,
+    config::{DetailedFinderConfig, ResponseFormat},
+};
+
+/// An HTTP echo service, and how to read the IP out of its response
+#[derive(Debug, Clone)]
+pub(super) struct HttpFinder {
+    url: Url,
+    format: Format,
+    headers: HeaderMap,
+}
+
+#[derive(Debug, Clone)]
+enum Format {
+    Plain,
+    Json(String),
+    Regex(Regex),
+    KeyValue(String),
+}
+
+impl HttpFinder {
+    pub(super) fn parse(url: Url, config: Option<&DetailedFinderConfig>) -> Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self {
+                url,
+                format: Format::Plain,
+                headers: HeaderMap::new(),
+            });
+        };
+
+        let format = match config.get_format() {
+            ResponseFormat::Plain => Format::Plain,
+            ResponseFormat::Json => Format::Json(
+                config
+                    .get_pointer()
+                    .context("the `json` format needs a `pointer` to the IP, eg. \"/ip\"")?
+                    .to_string(),
+            ),
+            ResponseFormat::Regex => {
+                let regex = config
+                    .get_regex()
+                    .context("the `regex` format needs a `regex` matching the IP")?;
+                Format::Regex(
+                    Regex::new(regex).with_context(|| format!("invalid regex `{regex}`"))?,
+                )
+            }
+            ResponseFormat::KeyValue => Format::KeyValue(config.get_key().to_string()),
+        };
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in config.get_headers() {
+            headers.insert(
+                HeaderName::try_from(name).with_context(|| format!("invalid header `{name}`"))?,
+                HeaderValue::try_from(value)
+                    .with_context(|| format!("invalid value for header `{name}`"))?,
+            );
+        }
+        if let Some(user_agent) = config.get_user_agent() {
+            headers.insert(
+                USER_AGENT,
+                HeaderValue::try_from(user_agent).context("invalid user-agent")?,
+            );
+        }
+
+        Ok(Self {
+            url,
+            format,
+            headers,
+        })
+    }
+
+    #[instrument(skip(self, client, retries, timeout), fields(url = %self.url))]
+    pub(super) async fn try_find(
+        &self,
+        client: &Client,
+        retries: u8,
+        timeout: Duration,
+        version: IpVersion,
+    ) -> Option<IpAddr> {
+        let url = &self.url;
+        tracing::trace!("Trying a URL");
+        for attempt in 0..=retries {
+            let final_attempt = attempt == retries;
+            if let Ok(response) = client
+                .get(url.clone())
+                .headers(self.headers.clone())
+                .timeout(timeout)
+                .send()
+                .await
+                .with_context(|| format!("failed on attempt {} for `{url}`", attempt + 1))
+                .warn_or_error(final_attempt)
+            {
+                tracing::debug!("Got a Response from {url} on attempt {}", attempt + 1);
+                if let Ok(response) = response
+                    .error_for_status()
+                    .context("server responded with an error")
+                    .warn_or_error(final_attempt)
+                {
+                    tracing::debug!("Server responded with success");
+                    if let Ok(text) = response
+                        .text()
+                        .await
+                        .context("failed to fetch text from successful response")
+                        .warn_or_error(final_attempt)
+                    {
+                        tracing::debug!("Server responded with `{}`", text.trim());
+                        if let Ok(ip) = self
+                            .extract(&text)
+                            .context("failed to read an IP from the response")
+                            .warn_or_error(final_attempt)
+                        {
+                            if version.matches(&ip) {
+                                return Some(ip);
+                            }
+                            tracing::warn!("Server responded with `{ip}`, expected {version}")
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn extract(&self, text: &str) -> Result<IpAddr> {
+        let json_ip: String;
+        let ip = match &self.format {
+            Format::Plain => text.trim(),
+            Format::Json(pointer) => {
+                let json: serde_json::Value =
+                    serde_json::from_str(text).context("response is not valid JSON")?;
+                json_ip = json
+                    .pointer(pointer)
+                    .with_context(|| format!("response has nothing at `{pointer}`"))?
+                    .as_str()
+                    .with_context(|| format!("response has no string at `{pointer}`"))?
+                    .to_string();
+                &json_ip
+            }
+            Format::Regex(regex) => {
+                let captures = regex
+                    .captures(text)
+                    .with_context(|| format!("response does not match `{regex}`"))?;
+                captures
+                    .name("ip")
+                    .or_else(|| captures.get(1))
+                    .or_else(|| captures.get(0))
+                    .map(|found| found.as_str())
+                    .unwrap_or_default()
+            }
+            Format::KeyValue(key) => text
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once('=')?;
+                    (name.trim() == key).then_some(value)
+                })
+                .with_context(|| format!("response has no `{key}=` line"))?,
+        };
+
+        let ip = ip.trim();
+        ip.parse()
+            .with_context(|| format!("`{ip}` is not an IP address"))
+    }
+}
+
+impl Display for HttpFinder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
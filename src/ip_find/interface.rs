@@ -0,0 +1,225 @@
+use std::{fmt::Display, net::IpAddr};
+
+use anyhow::{Context, Result};
+use tracing::instrument;
+
+use super::IpVersion;
+use crate::anyhow_tracing::Tracing;
+
+/// Reads the public address straight off a local interface, for hosts that
+/// hold it themselves
+#[derive(Debug, Clone)]
+pub(super) struct InterfaceFinder {
+    interface: String,
+    scope: Scope,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Global,
+    Site,
+    Link,
+    Host,
+}
+
+impl Scope {
+    fn parse(source: &str) -> Result<Self> {
+        match source.to_ascii_lowercase().as_str() {
+            "global" => Ok(Self::Global),
+            "site" => Ok(Self::Site),
+            "link" => Ok(Self::Link),
+            "host" => Ok(Self::Host),
+            _ => anyhow::bail!("unknown interface scope `{source}`"),
+        }
+    }
+
+    /// Best guess at the scope of `ip` where the OS doesn't report one
+    #[cfg(not(target_os = "linux"))]
+    fn of(ip: &IpAddr) -> Self {
+        match ip {
+            ip if ip.is_loopback() => Self::Host,
+            IpAddr::V4(ip) if ip.is_link_local() => Self::Link,
+            IpAddr::V6(ip) if ip.is_unicast_link_local() => Self::Link,
+            IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfec0 => Self::Site,
+            _ => Self::Global,
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Global => write!(f, "global"),
+            Self::Site => write!(f, "site"),
+            Self::Link => write!(f, "link"),
+            Self::Host => write!(f, "host"),
+        }
+    }
+}
+
+/// An address on the interface, before filtering
+struct Candidate {
+    ip: IpAddr,
+    scope: Scope,
+    /// Deprecated, temporary (privacy) or not yet usable IPv6 addresses
+    unstable: bool,
+}
+
+impl InterfaceFinder {
+    /// Parses `name[?scope=global|site|link|host]`, the scope defaults to global
+    pub(super) fn parse(source: &str) -> Result<Self> {
+        let (interface, query) = source.split_once('?').unwrap_or((source, ""));
+        let interface = interface.trim_end_matches('/');
+        anyhow::ensure!(!interface.is_empty(), "interface finder has no interface");
+
+        let mut scope = Scope::Global;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            match pair.split_once('=') {
+                Some(("scope", value)) => scope = Scope::parse(value)?,
+                _ => anyhow::bail!("unknown interface finder option `{pair}`"),
+            }
+        }
+
+        Ok(Self {
+            interface: interface.to_string(),
+            scope,
+        })
+    }
+
+    #[instrument]
+    pub(super) fn try_find(&self, version: IpVersion) -> Option<IpAddr> {
+        tracing::trace!("Reading interface addresses");
+        let candidates = candidates(&self.interface)
+            .with_context(|| format!("failed to read addresses of `{}`", self.interface))
+            .error()
+            .ok()?;
+
+        let mut found = candidates
+            .into_iter()
+            .filter(|candidate| version.matches(&candidate.ip))
+            .filter(|candidate| {
+                let usable = candidate.scope == self.scope && !candidate.unstable;
+                if !usable {
+                    tracing::debug!(
+                        "Skipping {} ({} scope{})",
+                        candidate.ip,
+                        candidate.scope,
+                        if candidate.unstable { ", unstable" } else { "" }
+                    );
+                }
+                usable
+            });
+
+        let ip = found.next().map(|candidate| candidate.ip);
+        match ip {
+            Some(ip) => {
+                for other in found {
+                    tracing::debug!("Ignoring additional address {}", other.ip);
+                }
+                tracing::debug!("Interface has `{ip}`");
+            }
+            None => tracing::warn!(
+                "Interface `{}` has no {} scope {version} address",
+                self.interface,
+                self.scope
+            ),
+        }
+        ip
+    }
+}
+
+impl Display for InterfaceFinder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "interface://{}", self.interface)?;
+        if self.scope != Scope::Global {
+            write!(f, "?scope={}", self.scope)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn candidates(interface: &str) -> Result<Vec<Candidate>> {
+    const UNSTABLE: u32 = libc::IFA_F_TEMPORARY
+        | libc::IFA_F_DEPRECATED
+        | libc::IFA_F_TENTATIVE
+        | libc::IFA_F_DADFAILED;
+
+    Ok(crate::netlink::interface_addresses(interface)?
+        .into_iter()
+        .filter_map(|address| {
+            let scope = match address.scope {
+                libc::RT_SCOPE_UNIVERSE => Scope::Global,
+                libc::RT_SCOPE_SITE => Scope::Site,
+                libc::RT_SCOPE_LINK => Scope::Link,
+                libc::RT_SCOPE_HOST => Scope::Host,
+                _ => return None,
+            };
+            Some(Candidate {
+                ip: address.ip,
+                scope,
+                unstable: address.flags & UNSTABLE != 0,
+            })
+        })
+        .collect())
+}
+
+/// getifaddrs(3) doesn't report address flags, so nothing is known to be
+/// unstable here
+#[cfg(not(target_os = "linux"))]
+fn candidates(interface: &str) -> Result<Vec<Candidate>> {
+    use std::{
+        ffi::CStr,
+        net::{Ipv4Addr, Ipv6Addr},
+    };
+
+    let mut addresses: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: `addresses` is a valid place for getifaddrs to write the list to
+    if unsafe { libc::getifaddrs(&mut addresses) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("getifaddrs failed");
+    }
+
+    let mut candidates = Vec::new();
+    let mut cursor = addresses;
+    while !cursor.is_null() {
+        // SAFETY: `cursor` is a non-null entry of the list getifaddrs returned
+        let entry = unsafe { &*cursor };
+        cursor = entry.ifa_next;
+        if entry.ifa_addr.is_null() {
+            continue;
+        }
+        // SAFETY: `ifa_name` is a nul terminated string for every entry
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) };
+        if name.to_bytes() != interface.as_bytes() {
+            continue;
+        }
+
+        // SAFETY: `ifa_addr` is non-null and its family says which sockaddr it is
+        let ip = unsafe {
+            match (*entry.ifa_addr).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let addr = &*(entry.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(entry.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            }
+        };
+        candidates.push(Candidate {
+            ip,
+            scope: Scope::of(&ip),
+            unstable: false,
+        });
+    }
+    // SAFETY: `addresses` came from getifaddrs and isn't used after this
+    unsafe { libc::freeifaddrs(addresses) };
+
+    anyhow::ensure!(
+        !candidates.is_empty(),
+        "interface `{interface}` does not exist or has no addresses"
+    );
+    Ok(candidates)
+}
@@ -0,0 +1,391 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::net::UdpSocket;
+use tracing::instrument;
+
+use super::IpVersion;
+use crate::anyhow_tracing::Tracing;
+
+pub(super) const DEFAULT_PORT: u16 = 5351;
+
+const NATPMP_VERSION: u8 = 0;
+const NATPMP_EXTERNAL_ADDRESS: u8 = 0;
+const NATPMP_RESPONSE: u8 = 0x80;
+
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+const PCP_RESPONSE: u8 = 0x80;
+const PCP_HEADER_LEN: usize = 24;
+const PCP_MAP_LEN: usize = 36;
+const PROTOCOL_UDP: u8 = 17;
+
+/// Which protocol to speak to the gateway
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Protocol {
+    /// NAT-PMP (RFC 6886), IPv4 only
+    NatPmp,
+    /// PCP (RFC 6887)
+    Pcp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NatPmp => write!(f, "NAT-PMP"),
+            Self::Pcp => write!(f, "PCP"),
+        }
+    }
+}
+
+/// Asks the gateway for its external address over NAT-PMP or PCP. Without
+/// an explicit `gateway` the IPv4 default gateway is used
+#[instrument(skip(retries, timeout))]
+pub(super) async fn try_gateway(
+    protocol: Protocol,
+    gateway: Option<&str>,
+    retries: u8,
+    timeout: Duration,
+    version: IpVersion,
+) -> Option<IpAddr> {
+    tracing::trace!("Trying a gateway");
+    for attempt in 0..=retries {
+        let final_attempt = attempt == retries;
+        if let Ok(ip) = tokio::time::timeout(timeout, query(protocol, gateway, version))
+            .await
+            .context("timed out waiting for the gateway")
+            .and_then(|result| result)
+            .with_context(|| format!("failed on attempt {} for {protocol}", attempt + 1))
+            .warn_or_error(final_attempt)
+        {
+            tracing::debug!("Gateway responded with `{ip}`");
+            if version.matches(&ip) {
+                return Some(ip);
+            }
+            tracing::warn!("Gateway responded with `{ip}`, expected {version}");
+        }
+    }
+    None
+}
+
+async fn query(protocol: Protocol, gateway: Option<&str>, version: IpVersion) -> Result<IpAddr> {
+    anyhow::ensure!(
+        protocol == Protocol::Pcp || version == IpVersion::V4,
+        "NAT-PMP can only report an IPv4 address, use PCP for IPv6"
+    );
+
+    let gateway = match gateway {
+        Some(gateway) => tokio::net::lookup_host(gateway)
+            .await
+            .with_context(|| format!("failed to resolve `{gateway}`"))?
+            .find(|address| version.matches(&address.ip()))
+            .with_context(|| format!("`{gateway}` has no {version} address"))?,
+        None => {
+            anyhow::ensure!(
+                version == IpVersion::V4,
+                "there is no default IPv6 gateway to ask, set one in the finder"
+            );
+            SocketAddr::new(IpAddr::V4(default_gateway()?), DEFAULT_PORT)
+        }
+    };
+    tracing::debug!("Asking gateway {gateway}");
+
+    let socket = UdpSocket::bind(SocketAddr::new(version.unspecified(), 0))
+        .await
+        .context("failed to bind gateway socket")?;
+    socket
+        .connect(gateway)
+        .await
+        .with_context(|| format!("failed to connect gateway socket to {gateway}"))?;
+
+    match protocol {
+        Protocol::NatPmp => natpmp(&socket).await,
+        Protocol::Pcp => pcp(&socket).await,
+    }
+}
+
+async fn natpmp(socket: &UdpSocket) -> Result<IpAddr> {
+    socket
+        .send(&[NATPMP_VERSION, NATPMP_EXTERNAL_ADDRESS])
+        .await
+        .context("failed to send NAT-PMP request")?;
+
+    let mut buf = [0; 16];
+    loop {
+        let len = socket
+            .recv(&mut buf)
+            .await
+            .context("failed to receive NAT-PMP response")?;
+        let response = &buf[..len];
+        if len < 12
+            || response[0] != NATPMP_VERSION
+            || response[1] != NATPMP_RESPONSE | NATPMP_EXTERNAL_ADDRESS
+        {
+            continue;
+        }
+
+        let result = u16::from_be_bytes([response[2], response[3]]);
+        anyhow::ensure!(
+            result == 0,
+            "gateway responded with NAT-PMP result {result}"
+        );
+        return Ok(IpAddr::V4(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        )));
+    }
+}
+
+/// PCP has no plain "external address" request, so this maps our own
+/// throwaway socket for a moment, reads the assigned address from the
+/// response and deletes the mapping again
+async fn pcp(socket: &UdpSocket) -> Result<IpAddr> {
+    let local = socket
+        .local_addr()
+        .context("failed to get gateway socket address")?;
+    let mut nonce = [0; 12];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut nonce)
+        .map_err(|_| anyhow::anyhow!("failed to generate PCP nonce"))?;
+
+    let response = pcp_map(socket, local, &nonce, 30).await?;
+    let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&response[44..60]).unwrap());
+    let ip = match ip.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(ip),
+    };
+
+    // A lifetime of zero deletes the mapping, failing to do so just leaves
+    // it to expire by itself
+    let _ = pcp_map(socket, local, &nonce, 0)
+        .await
+        .context("failed to delete PCP mapping")
+        .debug();
+
+    Ok(ip)
+}
+
+async fn pcp_map(
+    socket: &UdpSocket,
+    local: SocketAddr,
+    nonce: &[u8; 12],
+    lifetime: u32,
+) -> Result<[u8; PCP_HEADER_LEN + PCP_MAP_LEN]> {
+    let client = match local.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    // The all-zeroes address of our family tells the server which family
+    // of external address we want
+    let suggested = match local.ip() {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED,
+    };
+
+    let mut request = Vec::with_capacity(PCP_HEADER_LEN + PCP_MAP_LEN);
+    request.extend_from_slice(&[PCP_VERSION, PCP_MAP, 0, 0]);
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&client.octets());
+    request.extend_from_slice(nonce);
+    request.extend_from_slice(&[PROTOCOL_UDP, 0, 0, 0]);
+    request.extend_from_slice(&local.port().to_be_bytes());
+    request.extend_from_slice(&local.port().to_be_bytes());
+    request.extend_from_slice(&suggested.octets());
+    socket
+        .send(&request)
+        .await
+        .context("failed to send PCP MAP request")?;
+
+    let mut buf = [0; 1100];
+    loop {
+        let len = socket
+            .recv(&mut buf)
+            .await
+            .context("failed to receive PCP response")?;
+        let response = &buf[..len];
+        if len < 4 || response[1] != PCP_RESPONSE | PCP_MAP {
+            continue;
+        }
+        anyhow::ensure!(
+            response[0] == PCP_VERSION,
+            "gateway does not support PCP version {PCP_VERSION}"
+        );
+        anyhow::ensure!(
+            response[3] == 0,
+            "gateway responded with PCP result {}",
+            response[3]
+        );
+        if len < PCP_HEADER_LEN + PCP_MAP_LEN || response[24..36] != nonce[..] {
+            continue;
+        }
+        return Ok(response[..PCP_HEADER_LEN + PCP_MAP_LEN].try_into().unwrap());
+    }
+}
+
+/// The IPv4 default gateway from the kernel routing table
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Result<Ipv4Addr> {
+    let routes =
+        std::fs::read_to_string("/proc/net/route").context("failed to read /proc/net/route")?;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields.len() > 2 && fields[1] == "00000000").then(|| fields[2])
+        })
+        // The kernel prints the address as a native endian number
+        .find_map(|gateway| u32::from_str_radix(gateway, 16).ok())
+        .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()))
+        .filter(|gateway| !gateway.is_unspecified())
+        .context("no IPv4 default gateway found")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Result<Ipv4Addr> {
+    anyhow::bail!("finding the default gateway is only supported on Linux, set one in the finder")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTERNAL: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+    async fn responder() -> (UdpSocket, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+        (socket, address)
+    }
+
+    fn natpmp_response(opcode: u8, result: u16) -> Vec<u8> {
+        let mut response = vec![NATPMP_VERSION, NATPMP_RESPONSE | opcode];
+        response.extend_from_slice(&result.to_be_bytes());
+        response.extend_from_slice(&1234u32.to_be_bytes());
+        response.extend_from_slice(&EXTERNAL.octets());
+        response
+    }
+
+    /// Answers a PCP MAP request the way a gateway would, echoing the
+    /// mapping with `external` as the assigned address
+    fn pcp_response(request: &[u8], result: u8, external: Ipv6Addr) -> Vec<u8> {
+        let mut response = vec![PCP_VERSION, PCP_RESPONSE | PCP_MAP, 0, result];
+        response.extend_from_slice(&request[4..8]);
+        response.extend_from_slice(&[0; 16]);
+        response.extend_from_slice(&request[24..42]);
+        response.extend_from_slice(&request[42..44]);
+        response.extend_from_slice(&external.octets());
+        response
+    }
+
+    #[tokio::test]
+    async fn natpmp_reads_the_external_address() {
+        let (socket, gateway) = responder().await;
+        tokio::spawn(async move {
+            let mut buf = [0; 16];
+            let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], [NATPMP_VERSION, NATPMP_EXTERNAL_ADDRESS]);
+            // An answer to some other opcode is skipped
+            socket
+                .send_to(&natpmp_response(1, 0), client)
+                .await
+                .unwrap();
+            socket
+                .send_to(&natpmp_response(NATPMP_EXTERNAL_ADDRESS, 0), client)
+                .await
+                .unwrap();
+        });
+
+        let ip = query(Protocol::NatPmp, Some(&gateway), IpVersion::V4)
+            .await
+            .unwrap();
+        assert_eq!(ip, IpAddr::V4(EXTERNAL));
+    }
+
+    #[tokio::test]
+    async fn natpmp_fails_on_result_codes() {
+        let (socket, gateway) = responder().await;
+        tokio::spawn(async move {
+            let mut buf = [0; 16];
+            let (_, client) = socket.recv_from(&mut buf).await.unwrap();
+            socket
+                .send_to(&natpmp_response(NATPMP_EXTERNAL_ADDRESS, 3), client)
+                .await
+                .unwrap();
+        });
+
+        let err = query(Protocol::NatPmp, Some(&gateway), IpVersion::V4)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("result 3"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn natpmp_is_ipv4_only() {
+        assert!(
+            query(Protocol::NatPmp, Some("[::1]:5351"), IpVersion::V6)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn pcp_maps_and_deletes() {
+        let (socket, gateway) = responder().await;
+        let server = tokio::spawn(async move {
+            let mut buf = [0; 1100];
+            let mut lifetimes = Vec::new();
+            for _ in 0..2 {
+                let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+                let request = &buf[..len];
+                assert_eq!(len, PCP_HEADER_LEN + PCP_MAP_LEN);
+                assert_eq!(request[..2], [PCP_VERSION, PCP_MAP]);
+                assert_eq!(request[36], PROTOCOL_UDP);
+                assert_eq!(
+                    request[8..24],
+                    Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets()
+                );
+                assert_eq!(
+                    request[44..60],
+                    Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets()
+                );
+                lifetimes.push(u32::from_be_bytes(request[4..8].try_into().unwrap()));
+
+                // A response to someone else's nonce is skipped
+                let mut stray = pcp_response(request, 0, Ipv6Addr::LOCALHOST);
+                stray[24] ^= 0xff;
+                socket.send_to(&stray, client).await.unwrap();
+                let response = pcp_response(request, 0, EXTERNAL.to_ipv6_mapped());
+                socket.send_to(&response, client).await.unwrap();
+            }
+            lifetimes
+        });
+
+        let ip = query(Protocol::Pcp, Some(&gateway), IpVersion::V4)
+            .await
+            .unwrap();
+        assert_eq!(ip, IpAddr::V4(EXTERNAL));
+        assert_eq!(server.await.unwrap(), [30, 0]);
+    }
+
+    #[tokio::test]
+    async fn pcp_fails_on_result_codes() {
+        let (socket, gateway) = responder().await;
+        tokio::spawn(async move {
+            let mut buf = [0; 1100];
+            let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+            let response = pcp_response(&buf[..len], 8, Ipv6Addr::UNSPECIFIED);
+            socket.send_to(&response, client).await.unwrap();
+        });
+
+        let err = query(Protocol::Pcp, Some(&gateway), IpVersion::V4)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("result 8"), "{err}");
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::net::UdpSocket;
+use tracing::instrument;
+
+use super::IpVersion;
+use crate::anyhow_tracing::Tracing;
+
+pub(super) const DEFAULT_PORT: u16 = 3478;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+/// Asks a STUN server for our reflexive address with a Binding Request
+/// (RFC 5389)
+#[instrument(skip(retries, timeout))]
+pub(super) async fn try_stun(
+    server: &str,
+    retries: u8,
+    timeout: Duration,
+    version: IpVersion,
+) -> Option<IpAddr> {
+    tracing::trace!("Trying a STUN server");
+    for attempt in 0..=retries {
+        let final_attempt = attempt == retries;
+        if let Ok(ip) = tokio::time::timeout(timeout, binding(server, version))
+            .await
+            .context("timed out waiting for a STUN response")
+            .and_then(|result| result)
+            .with_context(|| format!("failed on attempt {} for `stun://{server}`", attempt + 1))
+            .warn_or_error(final_attempt)
+        {
+            tracing::debug!("STUN server responded with `{ip}`");
+            if version.matches(&ip) {
+                return Some(ip);
+            }
+            tracing::warn!("STUN server responded with `{ip}`, expected {version}");
+        }
+    }
+    None
+}
+
+async fn binding(server: &str, version: IpVersion) -> Result<IpAddr> {
+    let address = tokio::net::lookup_host(server)
+        .await
+        .with_context(|| format!("failed to resolve `{server}`"))?
+        .find(|address| version.matches(&address.ip()))
+        .with_context(|| format!("`{server}` has no {version} address"))?;
+
+    let socket = UdpSocket::bind(SocketAddr::new(version.unspecified(), 0))
+        .await
+        .context("failed to bind STUN socket")?;
+    socket
+        .connect(address)
+        .await
+        .with_context(|| format!("failed to connect STUN socket to {address}"))?;
+
+    // RFC 5389 wants the transaction ID to be cryptographically random, so
+    // off-path attackers can't spoof the response
+    let mut transaction = [0; 12];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut transaction)
+        .map_err(|_| anyhow::anyhow!("failed to generate STUN transaction ID"))?;
+    let mut request = Vec::with_capacity(HEADER_LEN);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction);
+    socket
+        .send(&request)
+        .await
+        .context("failed to send STUN Binding Request")?;
+
+    let mut buf = [0; 1024];
+    loop {
+        let len = socket
+            .recv(&mut buf)
+            .await
+            .context("failed to receive STUN response")?;
+        // Stray datagrams for other transactions are ignored, the caller's
+        // timeout bounds how long we keep waiting for ours
+        if let Some(ip) = parse_response(&buf[..len], &transaction)? {
+            return Ok(ip);
+        }
+    }
+}
+
+/// Parses a Binding Success Response, returning `None` if it belongs to a
+/// different transaction
+fn parse_response(response: &[u8], transaction: &[u8; 12]) -> Result<Option<IpAddr>> {
+    if response.len() < HEADER_LEN
+        || u32::from_be_bytes(response[4..8].try_into().unwrap()) != MAGIC_COOKIE
+        || response[8..20] != transaction[..]
+    {
+        return Ok(None);
+    }
+
+    let kind = u16::from_be_bytes(response[0..2].try_into().unwrap());
+    if kind != BINDING_SUCCESS {
+        anyhow::bail!("STUN server responded with message type {kind:#06x}");
+    }
+
+    let len = u16::from_be_bytes(response[2..4].try_into().unwrap()) as usize;
+    let mut attrs = response
+        .get(HEADER_LEN..HEADER_LEN + len)
+        .context("STUN response is shorter than its header claims")?;
+
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes(attrs[0..2].try_into().unwrap());
+        let len = u16::from_be_bytes(attrs[2..4].try_into().unwrap()) as usize;
+        let value = attrs
+            .get(4..4 + len)
+            .context("STUN attribute is longer than the response")?;
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return Ok(Some(parse_address(value, Some(transaction))?)),
+            // Pre RFC 5389 servers only send the plain address
+            ATTR_MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of 4 bytes
+        attrs = &attrs[(4 + len.next_multiple_of(4)).min(attrs.len())..];
+    }
+
+    mapped
+        .map(Some)
+        .context("STUN response has no mapped address")
+}
+
+/// Parses a (XOR-)MAPPED-ADDRESS value, un-XORing it with the magic cookie
+/// and transaction ID if `transaction` is given
+fn parse_address(value: &[u8], transaction: Option<&[u8; 12]>) -> Result<IpAddr> {
+    let family = *value
+        .get(1)
+        .context("STUN address attribute is too short")?;
+    let address = value.get(4..).unwrap_or_default();
+
+    let mut mask = [0; 16];
+    if let Some(transaction) = transaction {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction);
+    }
+
+    match (family, address.len()) {
+        (FAMILY_IPV4, 4) => {
+            let mut octets = [0; 4];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = address[i] ^ mask[i];
+            }
+            Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        (FAMILY_IPV6, 16) => {
+            let mut octets = [0; 16];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = address[i] ^ mask[i];
+            }
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => anyhow::bail!(
+            "STUN address attribute has unknown family {family:#04x} or length {}",
+            address.len()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSACTION: [u8; 12] = *b"transaction!";
+
+    fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut attr = Vec::new();
+        attr.extend_from_slice(&kind.to_be_bytes());
+        attr.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        attr.extend_from_slice(value);
+        attr.resize(attr.len().next_multiple_of(4), 0);
+        attr
+    }
+
+    /// A (XOR-)MAPPED-ADDRESS value for `ip`, XORed if `transaction` is given
+    fn address(ip: IpAddr, transaction: Option<&[u8; 12]>) -> Vec<u8> {
+        let (family, octets) = match ip {
+            IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+        };
+        let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+        mask.extend_from_slice(transaction.unwrap_or(&[0; 12]));
+        if transaction.is_none() {
+            mask = vec![0; 16];
+        }
+        let mut value = vec![0, family, 0x12, 0x34];
+        value.extend(octets.iter().zip(mask).map(|(octet, mask)| octet ^ mask));
+        value
+    }
+
+    fn response(kind: u16, transaction: &[u8; 12], attrs: &[Vec<u8>]) -> Vec<u8> {
+        let attrs = attrs.concat();
+        let mut response = Vec::new();
+        response.extend_from_slice(&kind.to_be_bytes());
+        response.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+        response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        response.extend_from_slice(transaction);
+        response.extend_from_slice(&attrs);
+        response
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn reads_xor_mapped_addresses() {
+        for expected in [ip("203.0.113.7"), ip("2001:db8::1234:5678")] {
+            let response = response(
+                BINDING_SUCCESS,
+                &TRANSACTION,
+                &[attribute(
+                    ATTR_XOR_MAPPED_ADDRESS,
+                    &address(expected, Some(&TRANSACTION)),
+                )],
+            );
+            assert_eq!(
+                parse_response(&response, &TRANSACTION).unwrap(),
+                Some(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn prefers_xor_mapped_over_mapped() {
+        let response = response(
+            BINDING_SUCCESS,
+            &TRANSACTION,
+            &[
+                attribute(ATTR_MAPPED_ADDRESS, &address(ip("192.0.2.1"), None)),
+                attribute(
+                    ATTR_XOR_MAPPED_ADDRESS,
+                    &address(ip("203.0.113.7"), Some(&TRANSACTION)),
+                ),
+            ],
+        );
+        assert_eq!(
+            parse_response(&response, &TRANSACTION).unwrap(),
+            Some(ip("203.0.113.7"))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_mapped_address() {
+        let response = response(
+            BINDING_SUCCESS,
+            &TRANSACTION,
+            &[attribute(
+                ATTR_MAPPED_ADDRESS,
+                &address(ip("203.0.113.7"), None),
+            )],
+        );
+        assert_eq!(
+            parse_response(&response, &TRANSACTION).unwrap(),
+            Some(ip("203.0.113.7"))
+        );
+    }
+
+    #[test]
+    fn skips_padded_attributes() {
+        // SOFTWARE, 5 bytes padded to 8, then the address
+        let response = response(
+            BINDING_SUCCESS,
+            &TRANSACTION,
+            &[
+                attribute(0x8022, b"stund"),
+                attribute(
+                    ATTR_XOR_MAPPED_ADDRESS,
+                    &address(ip("203.0.113.7"), Some(&TRANSACTION)),
+                ),
+            ],
+        );
+        assert_eq!(
+            parse_response(&response, &TRANSACTION).unwrap(),
+            Some(ip("203.0.113.7"))
+        );
+    }
+
+    #[test]
+    fn ignores_other_transactions() {
+        let mapped = [attribute(
+            ATTR_XOR_MAPPED_ADDRESS,
+            &address(ip("203.0.113.7"), Some(b"someone else")),
+        )];
+        let stray = response(BINDING_SUCCESS, b"someone else", &mapped);
+        assert_eq!(parse_response(&stray, &TRANSACTION).unwrap(), None);
+
+        let mut old = response(BINDING_SUCCESS, &TRANSACTION, &mapped);
+        old[4..8].copy_from_slice(&[0; 4]);
+        assert_eq!(parse_response(&old, &TRANSACTION).unwrap(), None);
+        assert_eq!(parse_response(&old[..12], &TRANSACTION).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_bad_responses() {
+        let error = response(0x0111, &TRANSACTION, &[]);
+        assert!(parse_response(&error, &TRANSACTION).is_err());
+
+        let empty = response(BINDING_SUCCESS, &TRANSACTION, &[]);
+        assert!(parse_response(&empty, &TRANSACTION).is_err());
+
+        let mut short = response(
+            BINDING_SUCCESS,
+            &TRANSACTION,
+            &[attribute(
+                ATTR_XOR_MAPPED_ADDRESS,
+                &address(ip("203.0.113.7"), Some(&TRANSACTION)),
+            )],
+        );
+        short.truncate(short.len() - 2);
+        assert!(parse_response(&short, &TRANSACTION).is_err());
+
+        let unknown_family = response(
+            BINDING_SUCCESS,
+            &TRANSACTION,
+            &[attribute(ATTR_MAPPED_ADDRESS, &[0, 0x03, 0, 0, 1, 2, 3, 4])],
+        );
+        assert!(parse_response(&unknown_family, &TRANSACTION).is_err());
+    }
+
+    #[tokio::test]
+    async fn binding_against_a_local_responder() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = responder.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let (len, client) = responder.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, HEADER_LEN);
+            assert_eq!(u16::from_be_bytes([buf[0], buf[1]]), BINDING_REQUEST);
+            let transaction: [u8; 12] = buf[8..20].try_into().unwrap();
+
+            // Someone else's answer first, which must be skipped
+            let stray = response(
+                BINDING_SUCCESS,
+                b"someone else",
+                &[attribute(
+                    ATTR_XOR_MAPPED_ADDRESS,
+                    &address(ip("192.0.2.1"), Some(b"someone else")),
+                )],
+            );
+            responder.send_to(&stray, client).await.unwrap();
+            let ours = response(
+                BINDING_SUCCESS,
+                &transaction,
+                &[attribute(
+                    ATTR_XOR_MAPPED_ADDRESS,
+                    &address(client.ip(), Some(&transaction)),
+                )],
+            );
+            responder.send_to(&ours, client).await.unwrap();
+        });
+
+        assert_eq!(
+            try_stun(&server, 0, Duration::from_secs(2), IpVersion::V4).await,
+            Some(ip("127.0.0.1"))
+        );
+    }
+}
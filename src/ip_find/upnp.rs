@@ -0,0 +1,382 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use reqwest::{Client, Url};
+use tokio::net::UdpSocket;
+use tracing::instrument;
+
+use super::IpVersion;
+use crate::anyhow_tracing::Tracing;
+
+const SSDP_ADDRESS: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// The WAN services that can report the external address, most routers only
+/// have one of them
+const WAN_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+
+/// Asks the router for its external address through UPnP IGD. Without a
+/// `description` URL the router is discovered with SSDP
+#[instrument(skip(client, retries, timeout))]
+pub(super) async fn try_upnp(
+    client: &Client,
+    description: Option<&Url>,
+    retries: u8,
+    timeout: Duration,
+    version: IpVersion,
+) -> Option<IpAddr> {
+    tracing::trace!("Trying UPnP IGD");
+    if version != IpVersion::V4 {
+        tracing::warn!("UPnP IGD can only report an IPv4 address");
+        return None;
+    }
+
+    for attempt in 0..=retries {
+        let final_attempt = attempt == retries;
+        if let Ok(ip) = tokio::time::timeout(timeout, query(client, description))
+            .await
+            .context("timed out waiting for the router")
+            .and_then(|result| result)
+            .with_context(|| format!("failed on attempt {} for UPnP IGD", attempt + 1))
+            .warn_or_error(final_attempt)
+        {
+            tracing::debug!("Router responded with `{ip}`");
+            if version.matches(&ip) {
+                return Some(ip);
+            }
+            tracing::warn!("Router responded with `{ip}`, expected {version}");
+        }
+    }
+    None
+}
+
+async fn query(client: &Client, description: Option<&Url>) -> Result<IpAddr> {
+    let description = match description {
+        Some(description) => description.clone(),
+        None => discover().await?,
+    };
+    tracing::debug!("Using gateway description at {description}");
+
+    let text = client
+        .get(description.clone())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("failed to fetch gateway description")?
+        .text()
+        .await
+        .context("failed to read gateway description")?;
+
+    let (service, control) = elements(&text, "service")
+        .find_map(|service| {
+            let kind = element(service, "serviceType")?;
+            if !WAN_SERVICES.iter().any(|wan| kind.starts_with(wan)) {
+                return None;
+            }
+            Some((kind, element(service, "controlURL")?))
+        })
+        .context("gateway has no WANIPConnection or WANPPPConnection service")?;
+
+    let base = match element(&text, "URLBase") {
+        Some(base) => Url::parse(base).context("gateway has an invalid URLBase")?,
+        None => description,
+    };
+    let control = base
+        .join(control)
+        .with_context(|| format!("gateway has an invalid controlURL `{control}`"))?;
+    tracing::debug!("Calling GetExternalIPAddress on {control} ({service})");
+
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+        s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body><u:GetExternalIPAddress xmlns:u=\"{service}\"/></s:Body>\
+        </s:Envelope>"
+    );
+    let text = client
+        .post(control)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{service}#GetExternalIPAddress\""))
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("GetExternalIPAddress call failed")?
+        .text()
+        .await
+        .context("failed to read GetExternalIPAddress response")?;
+
+    let ip = element(&text, "NewExternalIPAddress")
+        .context("GetExternalIPAddress response has no NewExternalIPAddress")?;
+    ip.parse()
+        .with_context(|| format!("router responded with invalid address `{ip}`"))
+}
+
+/// Finds the description URL of the first gateway answering an SSDP search
+async fn discover() -> Result<Url> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpVersion::V4.unspecified(), 0))
+        .await
+        .context("failed to bind SSDP socket")?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+        HOST: {SSDP_ADDRESS}\r\n\
+        MAN: \"ssdp:discover\"\r\n\
+        MX: 2\r\n\
+        ST: {SEARCH_TARGET}\r\n\r\n"
+    );
+    socket
+        .send_to(search.as_bytes(), SSDP_ADDRESS)
+        .await
+        .context("failed to send SSDP search")?;
+
+    let mut buf = [0; 2048];
+    loop {
+        let (len, from) = socket
+            .recv_from(&mut buf)
+            .await
+            .context("failed to receive SSDP response")?;
+        let response = String::from_utf8_lossy(&buf[..len]);
+        let location = response.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim())
+        });
+        match location.map(Url::parse) {
+            Some(Ok(location)) => return Ok(location),
+            Some(Err(err)) => tracing::debug!("Ignoring SSDP response from {from}: {err}"),
+            None => tracing::debug!("Ignoring SSDP response from {from} without a location"),
+        }
+    }
+}
+
+/// The text inside the first `<tag>` element of `xml`, ignoring namespaces
+fn element<'a>(xml: &'a str, tag: &'a str) -> Option<&'a str> {
+    elements(xml, tag).next().map(str::trim)
+}
+
+/// The contents of every `<tag>` element of `xml`, ignoring namespace prefixes
+fn elements<'a>(xml: &'a str, tag: &'a str) -> impl Iterator<Item = &'a str> {
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        loop {
+            let start = rest.find('<')?;
+            rest = &rest[start + 1..];
+            let end = rest.find('>')?;
+            let open = &rest[..end];
+            rest = &rest[end + 1..];
+            let name = open.split_whitespace().next().unwrap_or_default();
+            let local = name.rsplit(':').next().unwrap_or_default();
+            if local != tag || open.ends_with('/') {
+                continue;
+            }
+
+            let close = format!("</{name}>");
+            let end = rest.find(&close)?;
+            let content = &rest[..end];
+            rest = &rest[end + close.len()..];
+            return Some(content);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// A request the stand-in router received, as its path and SOAPAction
+    type Requests = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    /// Serves `description` at `/description.xml` and `reply` to any POST,
+    /// with `{base}` in the description standing in for the router's URL
+    async fn router(description: &str, reply: &str) -> (Url, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let description = description.replace("{base}", &base);
+        let reply = format!(
+            "<?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+            <s:Body>{reply}</s:Body></s:Envelope>"
+        );
+        let requests = Requests::default();
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // Headers, then as much body as they announce
+                let (head, body_len) = loop {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, _)) = text.split_once("\r\n\r\n") {
+                        let body_len = head
+                            .lines()
+                            .find_map(|line| {
+                                let (name, value) = line.split_once(':')?;
+                                name.eq_ignore_ascii_case("content-length")
+                                    .then(|| value.trim().parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        break (head.to_string(), body_len);
+                    }
+                };
+                while request.len() < head.len() + 4 + body_len {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                }
+
+                let path = head.split_whitespace().nth(1).unwrap().to_string();
+                let action = head.lines().find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("soapaction")
+                        .then(|| value.trim().to_string())
+                });
+                let body = if head.starts_with("GET /description.xml") {
+                    &description
+                } else {
+                    &reply
+                };
+                received.lock().unwrap().push((path, action));
+
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let url = Url::parse(&format!("{base}/description.xml")).unwrap();
+        (url, requests)
+    }
+
+    /// A description with the usual nesting, `services` being the WAN
+    /// device's `<service>` elements
+    fn description(url_base: &str, services: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\"?>\
+            <root xmlns=\"urn:schemas-upnp-org:device-1-0\">{url_base}\
+            <device><deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>\
+            <serviceList><service>\
+            <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/l3f</controlURL></service></serviceList>\
+            <deviceList><device>\
+            <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>\
+            <serviceList><service/>{services}</serviceList>\
+            </device></deviceList></device></root>"
+        )
+    }
+
+    fn service(kind: &str, control: &str) -> String {
+        format!(
+            "<service><serviceType>urn:schemas-upnp-org:service:{kind}</serviceType>\
+            <serviceId>urn:upnp-org:serviceId:WANConn1</serviceId>\
+            <controlURL>{control}</controlURL></service>"
+        )
+    }
+
+    fn external_address(kind: &str, ip: &str) -> String {
+        format!(
+            "<u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:{kind}\">\
+            <NewExternalIPAddress> {ip} </NewExternalIPAddress>\
+            </u:GetExternalIPAddressResponse>"
+        )
+    }
+
+    #[test]
+    fn elements_ignore_namespaces_and_empty_tags() {
+        let xml = "<a:list><a:item>one</a:item><item/><item x=\"1\">two</item></a:list>";
+        assert_eq!(elements(xml, "item").collect::<Vec<_>>(), ["one", "two"]);
+        assert_eq!(
+            element(xml, "list"),
+            Some("<a:item>one</a:item><item/><item x=\"1\">two</item>")
+        );
+        assert_eq!(element(xml, "missing"), None);
+    }
+
+    #[tokio::test]
+    async fn calls_a_relative_control_url() {
+        let (url, requests) = router(
+            &description("", &service("WANIPConnection:2", "/ctl/IPConn")),
+            &external_address("WANIPConnection:2", "203.0.113.7"),
+        )
+        .await;
+
+        let ip = query(&Client::new(), Some(&url)).await.unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+        assert_eq!(
+            requests.lock().unwrap()[1],
+            (
+                "/ctl/IPConn".to_string(),
+                Some(
+                    "\"urn:schemas-upnp-org:service:WANIPConnection:2#GetExternalIPAddress\""
+                        .to_string()
+                )
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn control_url_is_relative_to_the_url_base() {
+        let (url, requests) = router(
+            &description(
+                "<URLBase>{base}/igd/</URLBase>",
+                &service("WANIPConnection:1", "ctl"),
+            ),
+            &external_address("WANIPConnection:1", "203.0.113.7"),
+        )
+        .await;
+
+        query(&Client::new(), Some(&url)).await.unwrap();
+        assert_eq!(requests.lock().unwrap()[1].0, "/igd/ctl");
+    }
+
+    #[tokio::test]
+    async fn uses_a_ppp_connection() {
+        let (url, requests) = router(
+            &description("", &service("WANPPPConnection:1", "/ctl/PPPConn")),
+            &external_address("WANPPPConnection:1", "198.51.100.4"),
+        )
+        .await;
+
+        let ip = query(&Client::new(), Some(&url)).await.unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(198, 51, 100, 4)));
+        assert_eq!(requests.lock().unwrap()[1].0, "/ctl/PPPConn");
+    }
+
+    #[tokio::test]
+    async fn fails_without_a_wan_service() {
+        let (url, _) = router(&description("", ""), "").await;
+        let err = query(&Client::new(), Some(&url)).await.unwrap_err();
+        assert!(err.to_string().contains("no WANIPConnection"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn fails_without_an_external_address() {
+        let (url, _) = router(
+            &description("", &service("WANIPConnection:1", "/ctl")),
+            "<u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\"/>",
+        )
+        .await;
+
+        let err = query(&Client::new(), Some(&url)).await.unwrap_err();
+        assert!(err.to_string().contains("no NewExternalIPAddress"), "{err}");
+    }
+}
@@ -1,7 +1,15 @@
 pub mod anyhow_tracing;
 pub mod cloudflare;
 pub mod config;
+pub mod damping;
+mod dns;
 pub mod ip_find;
+pub mod memory;
+#[cfg(target_os = "linux")]
+pub mod netlink;
+pub mod provider;
+pub mod replace;
+pub mod rfc2136;
 pub mod state;
 
 use std::{
@@ -21,6 +29,8 @@ pub struct Environment {
 
     config_text: String,
     state_text: String,
+
+    config_created: bool,
 }
 
 impl Environment {
@@ -44,6 +54,8 @@ impl Environment {
                 .error()?,
         );
 
+        let config_created = !config_dir.join("config.toml").exists();
+
         let mut config = OpenOptions::new()
             .read(true)
             .write(true)
@@ -92,6 +104,7 @@ impl Environment {
             state,
             config_text,
             state_text,
+            config_created,
         })
     }
 
@@ -103,6 +116,12 @@ impl Environment {
         &self.state_text
     }
 
+    /// Whether config.toml has nothing in it yet, either because
+    /// [`Environment::initialize`] just created it or because it was left empty
+    pub fn config_is_blank(&self) -> bool {
+        self.config_created || self.config_text.trim().is_empty()
+    }
+
     #[instrument]
     pub fn write_config(&mut self, content: String) -> Result<()> {
         tracing::trace!("Writing to config.toml");
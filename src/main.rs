@@ -1,10 +1,16 @@
+use std::net::IpAddr;
+
 use anyhow::{Context, Result};
 use cloudflare_ddns::{
     Environment,
     anyhow_tracing::Tracing,
-    cloudflare::update_cloudflare,
     config::Config,
-    ip_find::{IpResult, find_public_ip},
+    damping,
+    ip_find::{FinderClients, IpResult, find_public_ip},
+    provider::{
+        Providers, cache_expired, remember_sent, update_records, verify_due, verify_records,
+    },
+    replace::replace_records,
     state::State,
 };
 use tokio::{signal::unix::Signal, time::Instant};
@@ -87,6 +93,20 @@ fn setup_signals() -> Result<(Signal, Signal)> {
     Ok((sigterm, sigint))
 }
 
+/// The `--daemon` and `--force` flags. Anything else is an error, so a typo
+/// doesn't quietly run a single check instead
+fn parse_args() -> Result<(bool, bool)> {
+    let (mut daemon, mut force) = (false, false);
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--daemon" => daemon = true,
+            "--force" => force = true,
+            _ => anyhow::bail!("Unknown argument `{arg}`, expected `--daemon` and/or `--force`"),
+        }
+    }
+    Ok((daemon, force))
+}
+
 #[tokio::main]
 async fn main() {
     setup_tracing();
@@ -99,10 +119,18 @@ async fn main() {
         }
     };
 
+    let (daemon, force) = match parse_args() {
+        Ok(flags) => flags,
+        Err(err) => {
+            tracing::error!("{err}");
+            std::process::exit(1)
+        }
+    };
+
     tracing::trace!("Starting main future selection");
     let start = Instant::now();
     let interrupted = tokio::select! {
-        result = run_service() => {
+        result = run_service(daemon, force) => {
             let duration = Instant::now().duration_since(start);
             if let Err(err) = result.with_context(|| format!("Service failed after {}ms", duration.as_millis())) {
                 tracing::error!("{err:?}");
@@ -129,26 +157,25 @@ async fn main() {
 }
 
 #[tracing::instrument]
-async fn run_service() -> Result<()> {
+async fn run_service(daemon: bool, mut force: bool) -> Result<()> {
     tracing::trace!("Running service");
     let mut env = Environment::initialize()
         .context("failed to initialize Environment")
         .error()?;
 
-    let config: Config = match toml::from_str(env.get_config())
-        .context("failed to read config, creating default")
-        .warn()
-    {
-        Ok(config) => config,
-        Err(_) => {
-            let config = Config::default();
-            env.write_config(
-                toml::to_string_pretty(&config).expect("failed to serialize default Config"),
-            )
-            .context("failed to write default config.toml")
-            .error()?;
-            config
-        }
+    let config = if env.config_is_blank() {
+        tracing::warn!("config.toml is empty, creating default");
+        let config = Config::default();
+        env.write_config(
+            toml::to_string_pretty(&config).expect("failed to serialize default Config"),
+        )
+        .context("failed to write default config.toml")
+        .error()?;
+        config
+    } else {
+        Config::parse(env.get_config())
+            .context("failed to read config.toml, leaving it untouched")
+            .error()?
     };
 
     let mut state: Option<State> = toml::from_str(env.get_state())
@@ -161,33 +188,207 @@ async fn run_service() -> Result<()> {
         return Ok(());
     }
 
-    tracing::info!("Searching for public IPv4 address...");
-    let ip = match find_public_ip(&config, &state).await {
-        IpResult::Found(ip) => ip,
-        IpResult::MatchesCache => {
-            tracing::info!("IP matched previously cached IP");
+    if config.get_ip_versions().is_empty() {
+        tracing::warn!("No DNS records configured, add a `[[records]]` entry to the config");
+        return Ok(());
+    }
+
+    let providers = Providers::new(&config)
+        .await
+        .context("failed to create DNS providers")
+        .error()?;
+    let clients = FinderClients::new()
+        .context("failed to create finder HTTP clients")
+        .error()?;
+
+    if !daemon {
+        return check_once(&config, &mut env, &providers, &clients, &mut state, force).await;
+    }
+
+    let daemon_config = config.get_daemon_config();
+    let interval = daemon_config.get_interval();
+    let jitter = daemon_config.get_jitter();
+    tracing::info!("Running as a daemon, checking every {interval:?} (+ up to {jitter:?} jitter)");
+
+    let mut watcher = setup_netlink(&config);
+
+    loop {
+        let start = Instant::now();
+        if let Err(err) =
+            check_once(&config, &mut env, &providers, &clients, &mut state, force).await
+        {
+            let duration = Instant::now().duration_since(start);
+            tracing::error!("Check failed after {}ms: {err:?}", duration.as_millis());
+        }
+        // --force only applies to the check made at startup
+        force = false;
+
+        let delay = interval + jitter.mul_f64(fastrand::f64());
+        tracing::debug!("Next check in {delay:?}");
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            result = network_changed(&mut watcher) => {
+                match result {
+                    Ok(()) => tracing::info!("Network change detected, checking now"),
+                    Err(err) => {
+                        tracing::error!("Stopped watching for network changes: {err:?}");
+                        watcher = None;
+                    }
+                }
+            },
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+type Watcher = cloudflare_ddns::netlink::NetlinkWatcher;
+#[cfg(not(target_os = "linux"))]
+type Watcher = std::convert::Infallible;
+
+#[cfg(target_os = "linux")]
+fn setup_netlink(config: &Config) -> Option<Watcher> {
+    let daemon_config = config.get_daemon_config();
+    if !daemon_config.get_netlink() {
+        return None;
+    }
+
+    Watcher::new(
+        daemon_config.get_interface().map(str::to_string),
+        daemon_config.get_debounce(),
+        daemon_config.get_cooldown(),
+    )
+    .context("failed to watch for network changes, falling back to polling")
+    .warn()
+    .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn setup_netlink(config: &Config) -> Option<Watcher> {
+    if config.get_daemon_config().get_netlink() {
+        tracing::warn!("Watching for network changes is only supported on Linux");
+    }
+    None
+}
+
+/// Resolves when the watcher sees a network change, never if there is no watcher
+async fn network_changed(watcher: &mut Option<Watcher>) -> Result<()> {
+    match watcher {
+        #[cfg(target_os = "linux")]
+        Some(watcher) => watcher.changed().await,
+        #[cfg(not(target_os = "linux"))]
+        Some(never) => match *never {},
+        None => std::future::pending().await,
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn check_once(
+    config: &Config,
+    env: &mut Environment,
+    providers: &Providers,
+    clients: &FinderClients,
+    state: &mut Option<State>,
+    force: bool,
+) -> Result<()> {
+    let mut failed = false;
+    let mut reports = Vec::new();
+    let verify = verify_due(config, state);
+
+    // Every version is found before any record is touched, so templates using
+    // both addresses are filled in with this check's instead of the cache's
+    let mut matched = Vec::new();
+    let mut settled = Vec::new();
+    for version in config.get_ip_versions() {
+        tracing::info!("Searching for public {version} address...");
+        match find_public_ip(config, clients, state, version).await {
+            IpResult::Found(ip) => {
+                tracing::debug!("Found new {version}: {ip}");
+                if damping::is_settled(config, state, ip, force) {
+                    settled.push((version, ip));
+                } else {
+                    tracing::info!(
+                        "Holding off on updating to {ip} until it is stable, use --force to update now"
+                    );
+                }
+            }
+            IpResult::MatchesCache(ip) => {
+                damping::reset(state, version);
+                tracing::info!("{version} matched previously cached IP");
+                matched.push((version, ip));
+            }
+            IpResult::NotFound => {
+                tracing::error!(
+                    "Failed to find public {version} address, all provided finders failed"
+                );
+                failed = true;
+            }
+        }
+    }
+    let found: Vec<IpAddr> = matched.iter().chain(&settled).map(|(_, ip)| *ip).collect();
+
+    for (version, ip) in matched {
+        let expired = cache_expired(config, state, version);
+        if verify || expired {
+            if expired {
+                tracing::info!("Cached {version} is older than the `max-age`");
+            }
+            tracing::info!("Checking DNS Records for drift...");
+            let mut verified = verify_records(config, providers, state, ip, &found).await;
+            if verified.iter().all(|report| report.result.is_ok()) {
+                remember_sent(config, state, ip);
+            }
+            reports.append(&mut verified);
+        } else {
             tracing::info!(
                 "NOTE: You can ignore the cache using the `ignore` key in the `cache` settings"
             );
-            return Ok(());
-        }
-        IpResult::NotFound => {
-            anyhow::bail!("Failed to find public IPv4 address, all provided finders failed");
         }
-    };
-
-    tracing::debug!("Found new IPv4: {ip}");
+    }
 
-    tracing::info!("Updating Cloudflare DNS Record...");
-    update_cloudflare(&config, &mut state, ip).await?;
+    for (version, ip) in settled {
+        let previous = state.as_ref().and_then(|state| version.cached(state));
+        tracing::info!("Updating DNS Records...");
+        let mut updated = update_records(config, providers, state, ip, &found).await;
+        let mut succeeded = updated.iter().all(|report| report.result.is_ok());
+        if succeeded && let Some(previous) = previous {
+            let mut replaced = replace_records(config, providers, state, previous, ip).await;
+            succeeded = replaced.iter().all(|report| report.result.is_ok());
+            updated.append(&mut replaced);
+        }
+        // Only cache the IP once every record has it, so failed updates and
+        // replacements are retried on the next run instead of being masked
+        // by the cache
+        if succeeded {
+            remember_sent(config, state, ip);
+        }
+        reports.append(&mut updated);
+    }
 
-    tracing::info!("Successfully updated Cloudflare DNS Record...");
+    for report in &reports {
+        match &report.result {
+            Ok(()) => tracing::info!("Updated {} to {}", report.record, report.ip),
+            Err(err) => {
+                tracing::error!(
+                    "Failed to update {} to {}: {err:#}",
+                    report.record,
+                    report.ip
+                );
+                failed = true;
+            }
+        }
+    }
 
     if state.is_some() {
-        env.write_state(toml::to_string_pretty(&state).expect("failed to serialize State"))
-            .context("failed to write default config.toml")
+        env.write_state(toml::to_string_pretty(state).expect("failed to serialize State"))
+            .context("failed to write state.toml")
             .error()?;
     }
 
+    if failed {
+        anyhow::bail!("Failed to update one or more DNS records");
+    } else if !reports.is_empty() {
+        tracing::info!("Successfully updated DNS Records...");
+    }
+
     Ok(())
 }
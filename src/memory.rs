@@ -0,0 +1,111 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{Context, Result};
+
+use crate::provider::{DnsProvider, Record, RecordNotFound, RecordParams};
+
+/// A [`DnsProvider`] that keeps its zones in memory, for tests and dry runs
+#[derive(Debug, Default)]
+pub struct MemoryProvider {
+    zones: Mutex<HashMap<String, Vec<Record>>>,
+}
+
+impl MemoryProvider {
+    pub fn with_records(zone: &str, records: Vec<Record>) -> Self {
+        Self {
+            zones: Mutex::new(HashMap::from([(zone.to_string(), records)])),
+        }
+    }
+
+    /// A snapshot of every record currently in `zone`
+    pub fn records(&self, zone: &str) -> Vec<Record> {
+        self.zones
+            .lock()
+            .expect("memory provider lock poisoned")
+            .get(zone)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl DnsProvider for MemoryProvider {
+    async fn find_records(&self, zone: &str, name: &str) -> Result<Vec<Record>> {
+        Ok(self
+            .records(zone)
+            .into_iter()
+            .filter(|record| record.name == name)
+            .collect())
+    }
+
+    async fn list_records(&self, zone: &str) -> Result<Vec<Record>> {
+        Ok(self.records(zone))
+    }
+
+    async fn find_zone(&self, name: &str) -> Result<Option<String>> {
+        let zones = self.zones.lock().expect("memory provider lock poisoned");
+        Ok(zones.contains_key(name).then(|| name.to_string()))
+    }
+
+    async fn create_record(&self, zone: &str, params: &RecordParams) -> Result<Record> {
+        let mut zones = self.zones.lock().expect("memory provider lock poisoned");
+        let records = zones.entry(zone.to_string()).or_default();
+        let next_id = records
+            .iter()
+            .filter_map(|record| record.id.parse::<u64>().ok())
+            .max()
+            .map_or(1, |id| id + 1);
+        let record = Record {
+            id: next_id.to_string(),
+            name: params.name.clone(),
+            content: params.content.clone(),
+            ttl: params.ttl.unwrap_or(1),
+            proxied: params.proxied,
+            comment: params.comment.clone(),
+            modified: None,
+        };
+        records.push(record.clone());
+        Ok(record)
+    }
+
+    async fn update_record(&self, zone: &str, id: &str, params: &RecordParams) -> Result<Record> {
+        let mut zones = self.zones.lock().expect("memory provider lock poisoned");
+        let record = zones
+            .get_mut(zone)
+            .and_then(|records| records.iter_mut().find(|record| record.id == id))
+            .ok_or(RecordNotFound)
+            .with_context(|| format!("no record `{id}` in zone `{zone}`"))?;
+        record.name = params.name.clone();
+        record.content = params.content.clone();
+        if let Some(ttl) = params.ttl {
+            record.ttl = ttl;
+        }
+        if params.proxied.is_some() {
+            record.proxied = params.proxied;
+        }
+        if params.comment.is_some() {
+            record.comment = params.comment.clone();
+        }
+        Ok(record.clone())
+    }
+
+    async fn delete_record(&self, zone: &str, id: &str) -> Result<()> {
+        let mut zones = self.zones.lock().expect("memory provider lock poisoned");
+        let records = zones
+            .get_mut(zone)
+            .with_context(|| format!("no zone `{zone}`"))?;
+        let index = records
+            .iter()
+            .position(|record| record.id == id)
+            .with_context(|| format!("no record `{id}` in zone `{zone}`"))?;
+        records.remove(index);
+        Ok(())
+    }
+
+    fn has_stable_ids(&self) -> bool {
+        true
+    }
+
+    fn can_list(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    ffi::{CStr, CString},
+    net::IpAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{Interest, unix::AsyncFd},
+    time::Instant,
+};
+use tracing::instrument;
+
+use crate::anyhow_tracing::Tracing;
+
+const NLMSG_HEADER_LEN: usize = 16;
+const IFADDRMSG_LEN: usize = 8;
+const RTMSG_LEN: usize = 12;
+
+/// Watches rtnetlink for address and default route changes on the WAN
+/// interface, so the daemon can check the public IP as soon as a link comes
+/// back instead of waiting for the next interval
+pub struct NetlinkWatcher {
+    socket: AsyncFd<OwnedFd>,
+    interface: Option<String>,
+    debounce: Duration,
+    cooldown: Duration,
+    last_trigger: Option<Instant>,
+    buf: Vec<u8>,
+}
+
+impl NetlinkWatcher {
+    /// Subscribes to changes on `interface`, or on every interface if `None`
+    #[instrument]
+    pub fn new(interface: Option<String>, debounce: Duration, cooldown: Duration) -> Result<Self> {
+        tracing::trace!("Subscribing to rtnetlink events");
+        let fd = open_socket(libc::SOCK_NONBLOCK)?;
+
+        // SAFETY: sockaddr_nl is plain old data, all zeroes is a valid value
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = (libc::RTMGRP_IPV4_IFADDR
+            | libc::RTMGRP_IPV6_IFADDR
+            | libc::RTMGRP_IPV4_ROUTE
+            | libc::RTMGRP_IPV6_ROUTE) as u32;
+        // SAFETY: `addr` is a valid sockaddr_nl and the length matches it
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("failed to bind netlink socket to address and route groups")
+                .error();
+        }
+
+        // SAFETY: `fd` is an open OwnedFd, which only closes it when the
+        // AsyncFd holding it is dropped
+        let socket = unsafe { AsyncFd::register_with_interest(fd, Interest::READABLE) }
+            .map_err(|err| err.into_parts().1)
+            .context("failed to register netlink socket with tokio")
+            .error()?;
+
+        Ok(Self {
+            socket,
+            interface,
+            debounce,
+            cooldown,
+            last_trigger: None,
+            buf: vec![0; 16 * 1024],
+        })
+    }
+
+    /// Resolves once a relevant change has been seen and the link has then
+    /// been quiet for the debounce period, and at most once per cooldown
+    pub async fn changed(&mut self) -> Result<()> {
+        // Wait for the first relevant event
+        while !self.next_batch().await? {}
+        tracing::debug!(
+            "Network change detected, debouncing for {:?}",
+            self.debounce
+        );
+
+        // Swallow everything until the link settles down, and until the
+        // cooldown since the last trigger is over
+        let mut quiet_until = Instant::now() + self.debounce;
+        loop {
+            let deadline = match self.last_trigger {
+                Some(last) => quiet_until.max(last + self.cooldown),
+                None => quiet_until,
+            };
+            tokio::select! {
+                relevant = self.next_batch() => {
+                    if relevant? {
+                        quiet_until = Instant::now() + self.debounce;
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+
+        self.last_trigger = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Reads one datagram of netlink messages, returning whether any of them
+    /// are relevant to the watched interface
+    async fn next_batch(&mut self) -> Result<bool> {
+        loop {
+            let mut guard = self
+                .socket
+                .readable()
+                .await
+                .context("failed to wait on netlink socket")?;
+            let buf = &mut self.buf;
+            match guard.try_io(|socket| {
+                // SAFETY: `buf` is valid for writes of its whole length
+                let len = unsafe {
+                    libc::recv(
+                        socket.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if len < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(len as usize)
+                }
+            }) {
+                Ok(Ok(len)) => return Ok(self.parse(len)),
+                Ok(Err(err)) if err.raw_os_error() == Some(libc::ENOBUFS) => {
+                    // The kernel dropped events, assume one of them mattered
+                    tracing::debug!("Netlink socket overran, treating as a change");
+                    return Ok(true);
+                }
+                Ok(Err(err)) => return Err(err).context("failed to read from netlink socket"),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn parse(&self, len: usize) -> bool {
+        let mut relevant = false;
+        let mut rest = &self.buf[..len];
+        while rest.len() >= NLMSG_HEADER_LEN {
+            let msg_len = u32::from_ne_bytes(rest[0..4].try_into().unwrap()) as usize;
+            let msg_type = u16::from_ne_bytes(rest[4..6].try_into().unwrap());
+            if msg_len < NLMSG_HEADER_LEN || msg_len > rest.len() {
+                break;
+            }
+            let payload = &rest[NLMSG_HEADER_LEN..msg_len];
+
+            relevant |= match msg_type {
+                libc::RTM_NEWADDR | libc::RTM_DELADDR if payload.len() >= IFADDRMSG_LEN => {
+                    let index = u32::from_ne_bytes(payload[4..8].try_into().unwrap());
+                    self.is_watched(index)
+                }
+                libc::RTM_NEWROUTE | libc::RTM_DELROUTE if payload.len() >= RTMSG_LEN => {
+                    // Only default routes (no destination prefix) matter
+                    let dst_len = payload[1];
+                    dst_len == 0
+                        && route_interface(&payload[RTMSG_LEN..])
+                            .is_none_or(|index| self.is_watched(index))
+                }
+                _ => false,
+            };
+
+            rest = &rest[align(msg_len).min(rest.len())..];
+        }
+        relevant
+    }
+
+    fn is_watched(&self, index: u32) -> bool {
+        let Some(interface) = &self.interface else {
+            return true;
+        };
+
+        // Interfaces like ppp0 get a new index every time they reconnect,
+        // so match on the name at the time of the event instead
+        let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+        // SAFETY: `name` is IF_NAMESIZE long, as if_indextoname requires
+        let found = unsafe { libc::if_indextoname(index, name.as_mut_ptr()) };
+        if found.is_null() {
+            // The interface is already gone, which is a change worth checking
+            return true;
+        }
+        // SAFETY: if_indextoname succeeded, so `name` holds a nul terminated string
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+        name.to_bytes() == interface.as_bytes()
+    }
+}
+
+/// An address assigned to a local interface, as reported by `RTM_GETADDR`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct InterfaceAddress {
+    pub(crate) ip: IpAddr,
+    /// One of the `RT_SCOPE_*` values
+    pub(crate) scope: u8,
+    /// The `IFA_F_*` flags
+    pub(crate) flags: u32,
+}
+
+/// Every address currently assigned to `interface`
+#[instrument]
+pub(crate) fn interface_addresses(interface: &str) -> Result<Vec<InterfaceAddress>> {
+    tracing::trace!("Dumping interface addresses");
+    let name = CString::new(interface).context("interface name contains a nul byte")?;
+    // SAFETY: `name` is a valid nul terminated string
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        anyhow::bail!("interface `{interface}` does not exist");
+    }
+
+    let fd = open_socket(0)?;
+    let mut request = [0u8; NLMSG_HEADER_LEN + IFADDRMSG_LEN];
+    request[0..4].copy_from_slice(&((NLMSG_HEADER_LEN + IFADDRMSG_LEN) as u32).to_ne_bytes());
+    request[4..6].copy_from_slice(&libc::RTM_GETADDR.to_ne_bytes());
+    request[6..8].copy_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    request[8..12].copy_from_slice(&1u32.to_ne_bytes());
+    request[NLMSG_HEADER_LEN] = libc::AF_UNSPEC as u8;
+    // SAFETY: `request` is valid for reads of its whole length
+    let sent = unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            request.as_ptr() as *const libc::c_void,
+            request.len(),
+            0,
+        )
+    };
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error())
+            .context("failed to send netlink address dump request");
+    }
+
+    let mut addresses = Vec::new();
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        // SAFETY: `buf` is valid for writes of its whole length
+        let len = unsafe {
+            libc::recv(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("failed to read netlink address dump");
+        }
+
+        let mut rest = &buf[..len as usize];
+        while rest.len() >= NLMSG_HEADER_LEN {
+            let msg_len = u32::from_ne_bytes(rest[0..4].try_into().unwrap()) as usize;
+            let msg_type = u16::from_ne_bytes(rest[4..6].try_into().unwrap());
+            if msg_len < NLMSG_HEADER_LEN || msg_len > rest.len() {
+                break;
+            }
+            let payload = &rest[NLMSG_HEADER_LEN..msg_len];
+
+            match msg_type as libc::c_int {
+                libc::NLMSG_DONE => return Ok(addresses),
+                libc::NLMSG_ERROR => anyhow::bail!("netlink address dump failed"),
+                _ if msg_type == libc::RTM_NEWADDR && payload.len() >= IFADDRMSG_LEN => {
+                    let msg_index = u32::from_ne_bytes(payload[4..8].try_into().unwrap());
+                    if msg_index == index
+                        && let Some(address) = parse_address(payload)
+                    {
+                        addresses.push(address);
+                    }
+                }
+                _ => {}
+            }
+
+            rest = &rest[align(msg_len).min(rest.len())..];
+        }
+    }
+}
+
+/// Parses an `ifaddrmsg` and its attributes
+fn parse_address(payload: &[u8]) -> Option<InterfaceAddress> {
+    let family = payload[0] as libc::c_int;
+    let mut flags = payload[2] as u32;
+    let scope = payload[3];
+
+    let mut address = None;
+    let mut local = None;
+    let mut attrs = &payload[IFADDRMSG_LEN..];
+    while attrs.len() >= 4 {
+        let len = u16::from_ne_bytes(attrs[0..2].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(attrs[2..4].try_into().unwrap());
+        if len < 4 || len > attrs.len() {
+            break;
+        }
+        let value = &attrs[4..len];
+        let ip = match (family, value.len()) {
+            (libc::AF_INET, 4) => Some(IpAddr::from(<[u8; 4]>::try_from(value).unwrap())),
+            (libc::AF_INET6, 16) => Some(IpAddr::from(<[u8; 16]>::try_from(value).unwrap())),
+            _ => None,
+        };
+        match kind {
+            libc::IFA_ADDRESS => address = ip,
+            libc::IFA_LOCAL => local = ip,
+            // The 8 bit flags in the header can't hold the newer flags
+            libc::IFA_FLAGS if value.len() == 4 => {
+                flags = u32::from_ne_bytes(value.try_into().unwrap())
+            }
+            _ => {}
+        }
+        attrs = &attrs[align(len).min(attrs.len())..];
+    }
+
+    // On point-to-point links IFA_ADDRESS is the peer, IFA_LOCAL is ours
+    Some(InterfaceAddress {
+        ip: local.or(address)?,
+        scope,
+        flags,
+    })
+}
+
+/// Opens a raw `NETLINK_ROUTE` socket with the extra `flags`
+fn open_socket(flags: libc::c_int) -> Result<OwnedFd> {
+    // SAFETY: plain socket(2) call, the returned fd is checked before use
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | flags,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error())
+            .context("failed to open netlink socket")
+            .error();
+    }
+    // SAFETY: `fd` was just returned by socket(2) and is owned by nothing else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// The `RTA_OIF` attribute of a route message, if it has one
+fn route_interface(mut attrs: &[u8]) -> Option<u32> {
+    while attrs.len() >= 4 {
+        let len = u16::from_ne_bytes(attrs[0..2].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(attrs[2..4].try_into().unwrap());
+        if len < 4 || len > attrs.len() {
+            break;
+        }
+        if kind == libc::RTA_OIF && len >= 8 {
+            return Some(u32::from_ne_bytes(attrs[4..8].try_into().unwrap()));
+        }
+        attrs = &attrs[align(len).min(attrs.len())..];
+    }
+    None
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
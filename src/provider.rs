@@ -0,0 +1,1463 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    anyhow_tracing::Tracing,
+    cloudflare::CloudflareProvider,
+    config::{Config, DuplicateStrategy, RecordConfig},
+    ip_find::IpVersion,
+    memory::MemoryProvider,
+    rfc2136::Rfc2136Provider,
+    state::{CachedRecord, OwnedRecord, State, unix_now},
+};
+
+/// A DNS record as seen by a [`DnsProvider`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub id: String,
+    pub name: String,
+    pub content: RecordContent,
+    pub ttl: u32,
+    pub proxied: Option<bool>,
+    pub comment: Option<String>,
+    /// When the record was last changed, for providers that track it
+    pub modified: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordContent {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Other { kind: String, content: String },
+}
+
+impl RecordContent {
+    pub fn kind(&self) -> &str {
+        match self {
+            Self::A(_) => "A",
+            Self::Aaaa(_) => "AAAA",
+            Self::Other { kind, .. } => kind,
+        }
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::A(ip) => Some(IpAddr::V4(*ip)),
+            Self::Aaaa(ip) => Some(IpAddr::V6(*ip)),
+            Self::Other { .. } => None,
+        }
+    }
+
+    /// Whether both hold the same data, ignoring the quoting some providers
+    /// add to TXT and SVCB values
+    pub fn matches(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                Self::Other { kind, content },
+                Self::Other {
+                    kind: other_kind,
+                    content: other_content,
+                },
+            ) => kind == other_kind && unquote(content) == unquote(other_content),
+            _ => self == other,
+        }
+    }
+}
+
+/// Strips the quoting providers may add in presentation format, joining TXT
+/// character-strings (`"a" "b"` is `ab`) and unquoting SvcParam values
+/// (`alpn="h2"` is `alpn=h2`). Quotes inside a value are kept
+fn unquote(content: &str) -> String {
+    let mut unquoted = String::with_capacity(content.len());
+    // Where the last quoted string ended, whitespace after it separates
+    // character-strings if another quoted string follows
+    let mut string_end = None;
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        let starts_value = matches!(unquoted.chars().next_back(), None | Some('=') | Some(' '));
+        if c != '"' || !starts_value {
+            if !c.is_whitespace() {
+                string_end = None;
+            }
+            unquoted.push(c);
+            continue;
+        }
+
+        if let Some(end) = string_end {
+            unquoted.truncate(end);
+        }
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                // Decimal escapes (`\032`) are left as they are
+                '\\' => match chars.next() {
+                    Some(digit) if digit.is_ascii_digit() => {
+                        unquoted.push('\\');
+                        unquoted.push(digit);
+                    }
+                    escaped => unquoted.extend(escaped),
+                },
+                c => unquoted.push(c),
+            }
+        }
+        string_end = Some(unquoted.len());
+    }
+    unquoted
+}
+
+impl From<IpAddr> for RecordContent {
+    fn from(value: IpAddr) -> Self {
+        match value {
+            IpAddr::V4(ip) => Self::A(ip),
+            IpAddr::V6(ip) => Self::Aaaa(ip),
+        }
+    }
+}
+
+impl Display for RecordContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::A(ip) => write!(f, "{ip}"),
+            Self::Aaaa(ip) => write!(f, "{ip}"),
+            Self::Other { content, .. } => write!(f, "{content}"),
+        }
+    }
+}
+
+/// The writable parts of a [`Record`], used for both creating and updating.
+/// Anything `None` is left as is when updating, and up to the provider when
+/// creating
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordParams {
+    pub name: String,
+    pub content: RecordContent,
+    pub ttl: Option<u32>,
+    pub proxied: Option<bool>,
+    pub comment: Option<String>,
+}
+
+/// Returned by [`DnsProvider::update_record`] when there is no record with
+/// the ID, eg. because it was deleted since its ID was cached
+#[derive(Debug)]
+pub struct RecordNotFound;
+
+impl Display for RecordNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the record does not exist")
+    }
+}
+
+impl Error for RecordNotFound {}
+
+/// A DNS backend that records can be managed through
+#[allow(async_fn_in_trait)]
+pub trait DnsProvider {
+    /// Every record in `zone` called `name`, of any type
+    async fn find_records(&self, zone: &str, name: &str) -> Result<Vec<Record>>;
+    /// Every record in `zone`, if [`DnsProvider::can_list`]
+    async fn list_records(&self, zone: &str) -> Result<Vec<Record>>;
+    /// The ID of the zone called `name`, if there is one
+    async fn find_zone(&self, name: &str) -> Result<Option<String>>;
+    async fn create_record(&self, zone: &str, params: &RecordParams) -> Result<Record>;
+    /// Fails with [`RecordNotFound`] if there is no record `id`, for
+    /// providers with stable IDs
+    async fn update_record(&self, zone: &str, id: &str, params: &RecordParams) -> Result<Record>;
+    async fn delete_record(&self, zone: &str, id: &str) -> Result<()>;
+    /// Whether a record keeps its ID when updated, making it worth caching
+    fn has_stable_ids(&self) -> bool;
+    /// Whether whole zones can be listed
+    fn can_list(&self) -> bool;
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    #[default]
+    Cloudflare,
+    /// Keeps records in memory for the lifetime of the process, for testing and dry runs
+    Memory,
+    Rfc2136,
+}
+
+impl Display for ProviderKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cloudflare => write!(f, "Cloudflare"),
+            Self::Memory => write!(f, "in-memory"),
+            Self::Rfc2136 => write!(f, "RFC 2136"),
+        }
+    }
+}
+
+pub enum Provider {
+    Cloudflare(CloudflareProvider),
+    Memory(MemoryProvider),
+    Rfc2136(Rfc2136Provider),
+}
+
+impl DnsProvider for Provider {
+    async fn find_records(&self, zone: &str, name: &str) -> Result<Vec<Record>> {
+        match self {
+            Self::Cloudflare(provider) => provider.find_records(zone, name).await,
+            Self::Memory(provider) => provider.find_records(zone, name).await,
+            Self::Rfc2136(provider) => provider.find_records(zone, name).await,
+        }
+    }
+
+    async fn list_records(&self, zone: &str) -> Result<Vec<Record>> {
+        match self {
+            Self::Cloudflare(provider) => provider.list_records(zone).await,
+            Self::Memory(provider) => provider.list_records(zone).await,
+            Self::Rfc2136(provider) => provider.list_records(zone).await,
+        }
+    }
+
+    async fn find_zone(&self, name: &str) -> Result<Option<String>> {
+        match self {
+            Self::Cloudflare(provider) => provider.find_zone(name).await,
+            Self::Memory(provider) => provider.find_zone(name).await,
+            Self::Rfc2136(provider) => provider.find_zone(name).await,
+        }
+    }
+
+    async fn create_record(&self, zone: &str, params: &RecordParams) -> Result<Record> {
+        match self {
+            Self::Cloudflare(provider) => provider.create_record(zone, params).await,
+            Self::Memory(provider) => provider.create_record(zone, params).await,
+            Self::Rfc2136(provider) => provider.create_record(zone, params).await,
+        }
+    }
+
+    async fn update_record(&self, zone: &str, id: &str, params: &RecordParams) -> Result<Record> {
+        match self {
+            Self::Cloudflare(provider) => provider.update_record(zone, id, params).await,
+            Self::Memory(provider) => provider.update_record(zone, id, params).await,
+            Self::Rfc2136(provider) => provider.update_record(zone, id, params).await,
+        }
+    }
+
+    async fn delete_record(&self, zone: &str, id: &str) -> Result<()> {
+        match self {
+            Self::Cloudflare(provider) => provider.delete_record(zone, id).await,
+            Self::Memory(provider) => provider.delete_record(zone, id).await,
+            Self::Rfc2136(provider) => provider.delete_record(zone, id).await,
+        }
+    }
+
+    fn has_stable_ids(&self) -> bool {
+        match self {
+            Self::Cloudflare(provider) => provider.has_stable_ids(),
+            Self::Memory(provider) => provider.has_stable_ids(),
+            Self::Rfc2136(provider) => provider.has_stable_ids(),
+        }
+    }
+
+    fn can_list(&self) -> bool {
+        match self {
+            Self::Cloudflare(provider) => provider.can_list(),
+            Self::Memory(provider) => provider.can_list(),
+            Self::Rfc2136(provider) => provider.can_list(),
+        }
+    }
+}
+
+/// One instance of every provider used by the configured records
+pub struct Providers {
+    providers: HashMap<ProviderKind, Provider>,
+}
+
+impl Providers {
+    #[instrument(skip(config))]
+    pub async fn new(config: &Config) -> Result<Self> {
+        tracing::trace!("Creating DNS providers");
+        let mut providers = HashMap::new();
+        for kind in config.get_records().map(RecordConfig::get_provider) {
+            if providers.contains_key(&kind) {
+                continue;
+            }
+
+            let provider = match kind {
+                ProviderKind::Cloudflare => Provider::Cloudflare(
+                    CloudflareProvider::new(config.get_cloudflare_config())
+                        .context("failed to create Cloudflare provider")
+                        .error()?,
+                ),
+                ProviderKind::Memory => Provider::Memory(MemoryProvider::default()),
+                ProviderKind::Rfc2136 => Provider::Rfc2136(
+                    Rfc2136Provider::new(
+                        config
+                            .get_rfc2136_config()
+                            .context("records use the `rfc2136` provider but there is no `[rfc2136]` config")
+                            .error()?,
+                    )
+                    .await
+                    .context("failed to create RFC 2136 provider")
+                    .error()?,
+                ),
+            };
+            providers.insert(kind, provider);
+        }
+
+        Ok(Self { providers })
+    }
+
+    pub fn insert(&mut self, kind: ProviderKind, provider: Provider) {
+        self.providers.insert(kind, provider);
+    }
+
+    pub(crate) fn get(&self, kind: ProviderKind) -> Result<&Provider> {
+        self.providers
+            .get(&kind)
+            .with_context(|| format!("no {kind} provider configured"))
+    }
+}
+
+/// The outcome of updating a single managed record
+#[derive(Debug)]
+pub struct RecordReport {
+    pub record: String,
+    pub ip: IpAddr,
+    pub result: Result<()>,
+}
+
+/// Points every record managing `ip` at it. `found` holds the addresses of
+/// every version found by this check, which templates prefer over the cache.
+/// Remembering `ip` as sent is left to the caller, see [`remember_sent`]
+#[instrument(skip(config, providers, state))]
+pub async fn update_records(
+    config: &Config,
+    providers: &Providers,
+    state: &mut Option<State>,
+    ip: IpAddr,
+    found: &[IpAddr],
+) -> Vec<RecordReport> {
+    tracing::trace!("Updating DNS records");
+    let previous = state
+        .as_ref()
+        .and_then(|state| IpVersion::of(&ip).cached(state));
+
+    let mut reports = Vec::new();
+    for record in config.get_records().filter(|record| record.manages(&ip)) {
+        tracing::info!("Updating {record} through {}...", record.get_provider());
+        let result = async {
+            let provider = providers.get(record.get_provider())?;
+            let Some(content) = record_content(record, ip, found, state) else {
+                return Ok(Change::Unchanged);
+            };
+            let zone = resolve_zone(config, provider, record, state).await?;
+            let change =
+                update_record(config, provider, record, &zone, content, previous, state).await?;
+            if let Change::Created(created) = &change {
+                remember_owned(state, &zone, created);
+            }
+            anyhow::Ok(change)
+        }
+        .await
+        .with_context(|| format!("failed to update {record}"))
+        .error();
+        if !matches!(result, Ok(Change::Unchanged)) {
+            reports.push(RecordReport {
+                record: record.to_string(),
+                ip,
+                result: result.map(|_| ()),
+            });
+        }
+    }
+
+    reports
+}
+
+/// Caches `ip` as held by every record as of now, if the cache is persisted
+pub fn remember_sent(config: &Config, state: &mut Option<State>, ip: IpAddr) {
+    if !config.get_cache_config().get_persist() {
+        return;
+    }
+    let state = state.get_or_insert_default();
+    let now = Some(unix_now());
+    match ip {
+        IpAddr::V4(ip) => {
+            state.last_sent_ip = Some(ip);
+            state.last_sent_ip_at = now;
+        }
+        IpAddr::V6(ip) => {
+            state.last_sent_ipv6 = Some(ip);
+            state.last_sent_ipv6_at = now;
+        }
+    }
+}
+
+/// Whether the cached `version` IP is older than the `max-age`. A cached IP
+/// without a timestamp, from before they were recorded, counts as expired
+pub fn cache_expired(config: &Config, state: &Option<State>, version: IpVersion) -> bool {
+    let (Some(max_age), Some(state)) = (config.get_cache_config().get_max_age(), state) else {
+        return false;
+    };
+    let sent_at = match version {
+        IpVersion::V4 => state.last_sent_ip_at,
+        IpVersion::V6 => state.last_sent_ipv6_at,
+    };
+    sent_at.is_none_or(|sent_at| unix_now().saturating_sub(sent_at) >= max_age.as_secs())
+}
+
+/// Checks the live records against the cached `ip`, which is the IP that
+/// was last sent, and restores any that were changed behind our back. Only
+/// records that drifted are reported. `found` is as in [`update_records`]
+#[instrument(skip(config, providers, state))]
+pub async fn verify_records(
+    config: &Config,
+    providers: &Providers,
+    state: &mut Option<State>,
+    ip: IpAddr,
+    found: &[IpAddr],
+) -> Vec<RecordReport> {
+    tracing::trace!("Verifying DNS records");
+
+    let mut reports = Vec::new();
+    for record in config.get_records().filter(|record| record.manages(&ip)) {
+        tracing::debug!("Verifying {record} through {}...", record.get_provider());
+        let result = async {
+            let provider = providers.get(record.get_provider())?;
+            let Some(content) = record_content(record, ip, found, state) else {
+                return Ok(Change::Unchanged);
+            };
+            let zone = resolve_zone(config, provider, record, state).await?;
+            let change = verify_record(config, provider, record, &zone, content, state).await?;
+            if let Change::Created(created) = &change {
+                remember_owned(state, &zone, created);
+            }
+            anyhow::Ok(change)
+        }
+        .await
+        .with_context(|| format!("failed to verify {record}"))
+        .error();
+        if !matches!(result, Ok(Change::Unchanged)) {
+            reports.push(RecordReport {
+                record: record.to_string(),
+                ip,
+                result: result.map(|_| ()),
+            });
+        }
+    }
+
+    reports
+}
+
+/// Counts this run towards the next drift check, returning whether it is due
+pub fn verify_due(config: &Config, state: &mut Option<State>) -> bool {
+    let (Some(every), Some(state)) = (config.get_cache_config().get_verify_every(), state) else {
+        return false;
+    };
+    state.unverified_runs += 1;
+    if state.unverified_runs < every {
+        return false;
+    }
+    state.unverified_runs = 0;
+    true
+}
+
+/// The ID of the zone `record_config` lives in. Unless `zone-identifier` is
+/// set, the zone is looked up by `zone-name`, or by walking up the labels of
+/// the record's name, and the ID cached in `state` if the cache is persisted
+pub(crate) async fn resolve_zone<P: DnsProvider>(
+    config: &Config,
+    provider: &P,
+    record_config: &RecordConfig,
+    state: &mut Option<State>,
+) -> Result<String> {
+    if let Some(id) = record_config.get_zone_id() {
+        return Ok(id.to_string());
+    }
+
+    let key = record_config
+        .get_zone_name()
+        .unwrap_or(record_config.get_record_name())
+        .trim_end_matches('.');
+    if let Some(id) = state.as_ref().and_then(|state| state.zones.get(key)) {
+        return Ok(id.clone());
+    }
+
+    let candidates = match record_config.get_zone_name() {
+        Some(_) => vec![key],
+        // Stop short of the TLD, nobody has that as their zone
+        None => {
+            let mut candidates = vec![key];
+            let mut rest = key;
+            while let Some((_, parent)) = rest.split_once('.')
+                && parent.contains('.')
+            {
+                candidates.push(parent);
+                rest = parent;
+            }
+            candidates
+        }
+    };
+    for name in candidates {
+        if let Some(id) = provider
+            .find_zone(name)
+            .await
+            .with_context(|| format!("failed to look up zone `{name}`"))
+            .debug()?
+        {
+            tracing::info!("Found zone `{name}` for {record_config}, its ID is `{id}`");
+            if config.get_cache_config().get_persist() {
+                state
+                    .get_or_insert_default()
+                    .zones
+                    .insert(key.to_string(), id.clone());
+            }
+            return Ok(id);
+        }
+    }
+
+    match record_config.get_zone_name() {
+        Some(name) => anyhow::bail!("there is no zone called `{name}`"),
+        None => anyhow::bail!(
+            "no zone contains `{key}`, set `zone-name` or `zone-identifier` to pick one"
+        ),
+    }
+}
+
+/// What had to be done to a record for it to hold the IP
+#[derive(Debug)]
+enum Change {
+    Unchanged,
+    Updated,
+    /// It didn't exist, so was created (see `create` in [`RecordConfig`])
+    Created(Record),
+}
+
+/// What `record_config` should hold now that the IP of its version is `ip`.
+/// A template using both versions takes the other IP from `found`, falling
+/// back to the cache. `None` if the other IP isn't known yet, in which case
+/// the record is left for that version's update to write
+fn record_content(
+    record_config: &RecordConfig,
+    ip: IpAddr,
+    found: &[IpAddr],
+    state: &Option<State>,
+) -> Option<RecordContent> {
+    let Some(template) = record_config.get_template() else {
+        return Some(RecordContent::from(ip));
+    };
+
+    let mut content = template.to_string();
+    for version in [IpVersion::V4, IpVersion::V6] {
+        let placeholder = version.placeholder();
+        if !content.contains(placeholder) {
+            continue;
+        }
+        let address = if version.matches(&ip) {
+            Some(ip)
+        } else {
+            found
+                .iter()
+                .find(|found| version.matches(found))
+                .copied()
+                .or_else(|| state.as_ref().and_then(|state| version.cached(state)))
+        };
+        let Some(address) = address else {
+            tracing::info!("{record_config} is waiting for a {version} to fill in `{placeholder}`");
+            return None;
+        };
+        content = content.replace(placeholder, &address.to_string());
+    }
+
+    Some(RecordContent::Other {
+        kind: record_config.get_record_type().to_string(),
+        content,
+    })
+}
+
+/// `previous` is the IP that was last sent, if known. A record whose ID is
+/// cached in `state` is updated straight away, only falling back to looking
+/// it up if it no longer exists
+#[instrument(skip(config, provider, state))]
+async fn update_record<P: DnsProvider>(
+    config: &Config,
+    provider: &P,
+    record_config: &RecordConfig,
+    zone: &str,
+    content: RecordContent,
+    previous: Option<IpAddr>,
+    state: &mut Option<State>,
+) -> Result<Change> {
+    let kind = content.kind().to_string();
+    if caches_id(config, provider, record_config)
+        && let Some(id) = cached_id(state, zone, record_config, &kind)
+    {
+        match write_cached_record(provider, record_config, zone, &id, content.clone()).await {
+            Ok(()) => return Ok(Change::Updated),
+            Err(err) if err.is::<RecordNotFound>() => {
+                tracing::info!(
+                    "Cached record `{id}` of {record_config} no longer exists, looking it up again"
+                );
+                forget_id(state, zone, record_config, &kind);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let Some(record) = find_record(provider, record_config, zone, &content, previous).await? else {
+        let change = create_record(provider, record_config, zone, content).await?;
+        if let Change::Created(created) = &change {
+            remember_id(config, provider, state, zone, record_config, created);
+        }
+        return Ok(change);
+    };
+    write_record(provider, record_config, zone, &record, content).await?;
+    remember_id(config, provider, state, zone, record_config, &record);
+    Ok(Change::Updated)
+}
+
+/// Restores the record if it no longer holds `content`. Always looks the
+/// record up, refreshing its ID cached in `state`
+#[instrument(skip(config, provider, state))]
+async fn verify_record<P: DnsProvider>(
+    config: &Config,
+    provider: &P,
+    record_config: &RecordConfig,
+    zone: &str,
+    content: RecordContent,
+    state: &mut Option<State>,
+) -> Result<Change> {
+    let Some(record) = find_record(provider, record_config, zone, &content, content.ip()).await?
+    else {
+        tracing::warn!("Drift detected: {record_config} was deleted outside of cloudflare-ddns");
+        forget_id(state, zone, record_config, content.kind());
+        let change = create_record(provider, record_config, zone, content).await?;
+        if let Change::Created(created) = &change {
+            remember_id(config, provider, state, zone, record_config, created);
+        }
+        return Ok(change);
+    };
+    remember_id(config, provider, state, zone, record_config, &record);
+    if record.content.matches(&content) {
+        tracing::debug!("{record_config} still holds `{content}`");
+        return Ok(Change::Unchanged);
+    }
+
+    tracing::warn!(
+        "Drift detected: {record_config} was changed to `{}` outside of cloudflare-ddns{}, restoring `{content}`",
+        record.content,
+        record
+            .modified
+            .as_ref()
+            .map(|modified| format!(" (last modified {modified})"))
+            .unwrap_or_default()
+    );
+    write_record(provider, record_config, zone, &record, content).await?;
+    Ok(Change::Updated)
+}
+
+/// The record of `content`'s type that is managed by `record_config`, if
+/// there is one. Where the name has several, the `duplicates` strategy picks
+/// one (collapsing deletes the rest)
+async fn find_record<P: DnsProvider>(
+    provider: &P,
+    record_config: &RecordConfig,
+    zone: &str,
+    content: &RecordContent,
+    previous: Option<IpAddr>,
+) -> Result<Option<Record>> {
+    let name = record_config.get_record_name();
+    let kind = content.kind();
+    let records = provider
+        .find_records(zone, name)
+        .await
+        .context("failed to request DNS record list")
+        .debug()
+        .debug_success("Successfully got DNS record list")
+        .error()?;
+
+    // Names often have several TXT records (eg. SPF and site verification),
+    // a templated record is told apart by the text before its first placeholder
+    let prefix = record_config
+        .get_template()
+        .and_then(|template| template.split('{').next())
+        .map(unquote)
+        .filter(|prefix| !prefix.is_empty());
+    let mut records: Vec<_> = records
+        .into_iter()
+        .filter(|record| record.content.kind() == kind)
+        .filter(|record| {
+            prefix
+                .as_ref()
+                .is_none_or(|prefix| unquote(&record.content.to_string()).starts_with(prefix))
+        })
+        .collect();
+
+    match record_config.get_duplicates() {
+        DuplicateStrategy::Fail => {}
+        DuplicateStrategy::Pin => {
+            let id = record_config
+                .get_record_id()
+                .context("the `pin` strategy needs a `record-id`")?;
+            return records
+                .into_iter()
+                .find(|record| record.id == id)
+                .map(Some)
+                .with_context(|| format!("there is no {kind} record `{id}` for {name}"));
+        }
+        DuplicateStrategy::Comment => {
+            let comment = record_config
+                .get_match_comment()
+                .context("the `comment` strategy needs a `match-comment`")?;
+            records.retain(|record| {
+                record
+                    .comment
+                    .as_deref()
+                    .is_some_and(|record_comment| record_comment.contains(comment))
+            });
+        }
+        DuplicateStrategy::Previous if records.len() > 1 => {
+            let previous = previous.with_context(|| {
+                format!("multiple {kind} records for {name}, and no previous IP to pick one by")
+            })?;
+            records.retain(|record| record.content.ip() == Some(previous));
+            anyhow::ensure!(
+                !records.is_empty(),
+                "multiple {kind} records for {name}, but none of them hold the previous IP {previous}"
+            );
+        }
+        DuplicateStrategy::Collapse if records.len() > 1 => {
+            // Keep the one that already holds the IP if there is one, it may
+            // not need updating at all
+            let keep = records
+                .iter()
+                .position(|record| record.content.matches(content))
+                .unwrap_or(0);
+            let record = records.swap_remove(keep);
+            for duplicate in records {
+                tracing::info!(
+                    "Deleting duplicate {kind} record `{}` ({}) for {name}",
+                    duplicate.id,
+                    duplicate.content
+                );
+                provider
+                    .delete_record(zone, &duplicate.id)
+                    .await
+                    .context("failed to delete duplicate DNS record")
+                    .debug()
+                    .debug_success("Successfully deleted duplicate DNS record")
+                    .error()?;
+            }
+            return Ok(Some(record));
+        }
+        DuplicateStrategy::Previous | DuplicateStrategy::Collapse => {}
+    }
+
+    let mut records = records.into_iter();
+    let record = records.next();
+    if records.next().is_some() {
+        anyhow::bail!(
+            "multiple {kind} records retrieved for {name}, case is ambiguous, see the `duplicates` setting"
+        )
+    }
+
+    Ok(record)
+}
+
+/// Creates the missing record, if `record_config` allows it
+async fn create_record<P: DnsProvider>(
+    provider: &P,
+    record_config: &RecordConfig,
+    zone: &str,
+    content: RecordContent,
+) -> Result<Change> {
+    anyhow::ensure!(
+        record_config.get_create(),
+        "failed to find any {} records for {}, set `create = true` to create it",
+        content.kind(),
+        record_config.get_record_name()
+    );
+
+    tracing::info!("{record_config} does not exist, creating it...");
+    let params = RecordParams {
+        name: record_config.get_record_name().to_string(),
+        content,
+        ttl: record_config.get_ttl(),
+        proxied: record_config.get_proxied(),
+        comment: render_comment(record_config),
+    };
+    let record = provider
+        .create_record(zone, &params)
+        .await
+        .context("failed to create DNS record")
+        .debug()
+        .debug_success("Successfully created DNS record")
+        .error()?;
+
+    Ok(Change::Created(record))
+}
+
+async fn write_record<P: DnsProvider>(
+    provider: &P,
+    record_config: &RecordConfig,
+    zone: &str,
+    record: &Record,
+    content: RecordContent,
+) -> Result<()> {
+    // Anything not set in the config is preserved, the TTL is always passed
+    // on since providers that replace the record would otherwise reset it
+    let params = RecordParams {
+        name: record.name.clone(),
+        content,
+        ttl: record_config.get_ttl().or(Some(record.ttl)),
+        proxied: record_config.get_proxied().or(record.proxied),
+        comment: render_comment(record_config),
+    };
+    provider
+        .update_record(zone, &record.id, &params)
+        .await
+        .context("failed to update DNS record")
+        .debug()
+        .debug_success("Successfully updated DNS record")
+        .error()?;
+
+    Ok(())
+}
+
+/// The configured comment, with `{timestamp}` filled in as the current UTC time
+fn render_comment(record_config: &RecordConfig) -> Option<String> {
+    record_config.get_comment().map(|comment| {
+        comment.replace(
+            "{timestamp}",
+            &chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        )
+    })
+}
+
+/// Updates the record `id` without knowing its current values, leaving
+/// anything not set in the config as is
+async fn write_cached_record<P: DnsProvider>(
+    provider: &P,
+    record_config: &RecordConfig,
+    zone: &str,
+    id: &str,
+    content: RecordContent,
+) -> Result<()> {
+    let params = RecordParams {
+        name: record_config.get_record_name().to_string(),
+        content,
+        ttl: record_config.get_ttl(),
+        proxied: record_config.get_proxied(),
+        comment: render_comment(record_config),
+    };
+    provider
+        .update_record(zone, id, &params)
+        .await
+        .context("failed to update cached DNS record")
+        .debug()
+        .debug_success("Successfully updated cached DNS record")?;
+
+    Ok(())
+}
+
+fn is_cached(cached: &CachedRecord, zone: &str, record_config: &RecordConfig, kind: &str) -> bool {
+    cached.zone == zone
+        && cached.name == record_config.get_record_name()
+        && cached.kind == kind
+        && cached.template.as_deref() == record_config.get_template()
+}
+
+/// The ID of the record last found for `record_config`
+fn cached_id(
+    state: &Option<State>,
+    zone: &str,
+    record_config: &RecordConfig,
+    kind: &str,
+) -> Option<String> {
+    state
+        .as_ref()?
+        .records
+        .iter()
+        .find(|cached| is_cached(cached, zone, record_config, kind))
+        .map(|cached| cached.id.clone())
+}
+
+/// Whether the ID of the record managed by `record_config` is cached. Only
+/// with the `fail` duplicates strategy, the others have to see every record
+/// of the name to pick theirs
+fn caches_id<P: DnsProvider>(config: &Config, provider: &P, record_config: &RecordConfig) -> bool {
+    provider.has_stable_ids()
+        && config.get_cache_config().get_persist()
+        && record_config.get_duplicates() == DuplicateStrategy::Fail
+}
+
+/// Notes in `state` that `record` is the one managed by `record_config`, if
+/// its ID is cached
+fn remember_id<P: DnsProvider>(
+    config: &Config,
+    provider: &P,
+    state: &mut Option<State>,
+    zone: &str,
+    record_config: &RecordConfig,
+    record: &Record,
+) {
+    if !caches_id(config, provider, record_config) {
+        return;
+    }
+    let kind = record.content.kind();
+    forget_id(state, zone, record_config, kind);
+    state.get_or_insert_default().records.push(CachedRecord {
+        zone: zone.to_string(),
+        name: record_config.get_record_name().to_string(),
+        kind: kind.to_string(),
+        template: record_config.get_template().map(str::to_string),
+        id: record.id.clone(),
+    });
+}
+
+fn forget_id(state: &mut Option<State>, zone: &str, record_config: &RecordConfig, kind: &str) {
+    if let Some(state) = state {
+        state
+            .records
+            .retain(|cached| !is_cached(cached, zone, record_config, kind));
+    }
+}
+
+/// Notes in `state` that `record` was created by us
+fn remember_owned(state: &mut Option<State>, zone: &str, record: &Record) {
+    let owned = &mut state.get_or_insert_default().owned;
+    owned.retain(|owned| {
+        !(owned.zone == zone && owned.name == record.name && owned.kind == record.content.kind())
+    });
+    owned.push(OwnedRecord {
+        zone: zone.to_string(),
+        name: record.name.clone(),
+        kind: record.content.kind().to_string(),
+        id: record.id.clone(),
+        created: unix_now(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "zone";
+    const NAME: &str = "www.example.com";
+
+    /// A memory-only config with `records` appended
+    fn config(records: &str) -> Config {
+        Config::parse(&format!(
+            "active = true\n[ip-find]\nfinders = []\n[cloudflare]\napi-key = \"\"\n{records}"
+        ))
+        .unwrap()
+    }
+
+    /// A config managing the A record of `NAME`, with `extra` keys
+    fn a_config(extra: &str) -> Config {
+        config(&format!(
+            "[[records]]\nzone-identifier = \"{ZONE}\"\ndns-record-name = \"{NAME}\"\nprovider = \"memory\"\n{extra}\n"
+        ))
+    }
+
+    fn record(id: &str, name: &str, content: RecordContent) -> Record {
+        Record {
+            id: id.to_string(),
+            name: name.to_string(),
+            content,
+            ttl: 60,
+            proxied: None,
+            comment: None,
+            modified: None,
+        }
+    }
+
+    fn a(ip: &str) -> RecordContent {
+        RecordContent::A(ip.parse().unwrap())
+    }
+
+    fn other(kind: &str, content: &str) -> RecordContent {
+        RecordContent::Other {
+            kind: kind.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    async fn providers(config: &Config, records: Vec<Record>) -> Providers {
+        let mut providers = Providers::new(config).await.unwrap();
+        providers.insert(
+            ProviderKind::Memory,
+            Provider::Memory(MemoryProvider::with_records(ZONE, records)),
+        );
+        providers
+    }
+
+    fn zone(providers: &Providers) -> Vec<Record> {
+        match providers.get(ProviderKind::Memory).unwrap() {
+            Provider::Memory(memory) => memory.records(ZONE),
+            _ => unreachable!(),
+        }
+    }
+
+    /// The IDs and contents in the zone, for comparing at a glance
+    fn contents(providers: &Providers) -> Vec<(String, String)> {
+        zone(providers)
+            .into_iter()
+            .map(|record| (record.id, record.content.to_string()))
+            .collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(id, content)| (id.to_string(), content.to_string()))
+            .collect()
+    }
+
+    fn state(ip: &str) -> Option<State> {
+        Some(State {
+            last_sent_ip: Some(ip.parse().unwrap()),
+            ..Default::default()
+        })
+    }
+
+    fn assert_ok(reports: &[RecordReport]) {
+        assert!(!reports.is_empty());
+        for report in reports {
+            assert!(
+                report.result.is_ok(),
+                "{}: {:?}",
+                report.record,
+                report.result
+            );
+        }
+    }
+
+    fn assert_err(reports: &[RecordReport], expected: &str) {
+        assert_eq!(reports.len(), 1);
+        let err = format!("{:#}", reports[0].result.as_ref().unwrap_err());
+        assert!(err.contains(expected), "{err}");
+    }
+
+    fn cached_ids(state: &Option<State>) -> Vec<&str> {
+        state
+            .iter()
+            .flat_map(|state| &state.records)
+            .map(|cached| cached.id.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn updates_the_record_and_caches_its_id() {
+        let config = a_config("");
+        let providers = providers(&config, vec![record("1", NAME, a("192.0.2.1"))]).await;
+        let mut state = state("192.0.2.1");
+
+        assert_ok(&update_records(&config, &providers, &mut state, ip("192.0.2.2"), &[]).await);
+
+        assert_eq!(contents(&providers), pairs(&[("1", "192.0.2.2")]));
+        assert_eq!(zone(&providers)[0].ttl, 60);
+        assert_eq!(cached_ids(&state), ["1"]);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_cached_unless_persisted() {
+        let config = config(&format!(
+            "[cache]\npersist = false\n[[records]]\nzone-name = \"{ZONE}\"\ndns-record-name = \"{NAME}\"\nprovider = \"memory\"\n"
+        ));
+        let providers = providers(&config, vec![record("1", NAME, a("192.0.2.1"))]).await;
+        let mut state = None;
+
+        assert_ok(&update_records(&config, &providers, &mut state, ip("192.0.2.2"), &[]).await);
+        assert!(
+            verify_records(&config, &providers, &mut state, ip("192.0.2.2"), &[])
+                .await
+                .is_empty()
+        );
+
+        assert_eq!(contents(&providers), pairs(&[("1", "192.0.2.2")]));
+        assert!(state.is_none(), "{state:?}");
+    }
+
+    /// A dual-stack SPF record of `NAME`, after `cache`
+    fn spf_config(cache: &str) -> Config {
+        config(&format!(
+            "{cache}\n[[records]]\nzone-identifier = \"{ZONE}\"\ndns-record-name = \"{NAME}\"\nprovider = \"memory\"\nrecord-type = \"TXT\"\ntemplate = \"v=spf1 ip4:{{ipv4}} ip6:{{ipv6}} -all\"\n"
+        ))
+    }
+
+    fn spf_zone() -> Vec<Record> {
+        vec![
+            record("1", NAME, other("TXT", "google-site-verification=abc")),
+            record(
+                "2",
+                NAME,
+                other("TXT", "v=spf1 ip4:192.0.2.1 ip6:2001:db8::1 -all"),
+            ),
+        ]
+    }
+
+    #[tokio::test]
+    async fn dual_template_uses_the_addresses_found_together() {
+        let config = spf_config("[cache]\npersist = false");
+        let providers = providers(&config, spf_zone()).await;
+        let found = [ip("192.0.2.2"), ip("2001:db8::2")];
+        let mut state = None;
+
+        for ip in found {
+            assert_ok(&update_records(&config, &providers, &mut state, ip, &found).await);
+        }
+
+        assert_eq!(
+            contents(&providers),
+            pairs(&[
+                ("1", "google-site-verification=abc"),
+                ("2", "v=spf1 ip4:192.0.2.2 ip6:2001:db8::2 -all")
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn dual_template_waits_for_the_other_version() {
+        let config = spf_config("");
+        let providers = providers(&config, spf_zone()).await;
+        let mut state = None;
+
+        // Without an IPv6 the record is left alone, without failing, so the
+        // IPv4 still counts as sent and the IPv6 update can fill it in later
+        let reports = update_records(&config, &providers, &mut state, ip("192.0.2.2"), &[]).await;
+        assert!(reports.is_empty());
+        remember_sent(&config, &mut state, ip("192.0.2.2"));
+        assert_eq!(
+            contents(&providers),
+            pairs(&[
+                ("1", "google-site-verification=abc"),
+                ("2", "v=spf1 ip4:192.0.2.1 ip6:2001:db8::1 -all"),
+            ])
+        );
+
+        assert_ok(&update_records(&config, &providers, &mut state, ip("2001:db8::2"), &[]).await);
+        assert_eq!(
+            contents(&providers)[1].1,
+            "v=spf1 ip4:192.0.2.2 ip6:2001:db8::2 -all"
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_configured_ttl_and_comment() {
+        let config = a_config("ttl = 300\ncomment = \"ddns\"");
+        let providers = providers(&config, vec![record("1", NAME, a("192.0.2.1"))]).await;
+
+        assert_ok(&update_records(&config, &providers, &mut None, ip("192.0.2.2"), &[]).await);
+
+        let updated = &zone(&providers)[0];
+        assert_eq!(updated.ttl, 300);
+        assert_eq!(updated.comment.as_deref(), Some("ddns"));
+    }
+
+    fn round_robin() -> Vec<Record> {
+        let mut pinned = record("2", NAME, a("192.0.2.2"));
+        pinned.comment = Some("home router".to_string());
+        vec![
+            record("1", NAME, a("192.0.2.1")),
+            pinned,
+            record("3", NAME, a("192.0.2.3")),
+            record("4", NAME, other("TXT", "unrelated")),
+        ]
+    }
+
+    #[tokio::test]
+    async fn duplicates_fail_by_default() {
+        let config = a_config("");
+        let providers = providers(&config, round_robin()).await;
+
+        let reports = update_records(&config, &providers, &mut None, ip("192.0.2.9"), &[]).await;
+
+        assert_err(&reports, "multiple A records");
+        assert_eq!(
+            contents(&providers),
+            pairs(&[
+                ("1", "192.0.2.1"),
+                ("2", "192.0.2.2"),
+                ("3", "192.0.2.3"),
+                ("4", "unrelated")
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicates_pin_updates_the_pinned_record() {
+        let config = a_config("duplicates = \"pin\"\nrecord-id = \"3\"");
+        let providers = providers(&config, round_robin()).await;
+
+        assert_ok(&update_records(&config, &providers, &mut None, ip("192.0.2.9"), &[]).await);
+
+        assert_eq!(zone(&providers)[2].content, a("192.0.2.9"));
+        assert_eq!(zone(&providers)[0].content, a("192.0.2.1"));
+
+        let config = a_config("duplicates = \"pin\"\nrecord-id = \"7\"");
+        let reports = update_records(&config, &providers, &mut None, ip("192.0.2.9"), &[]).await;
+        assert_err(&reports, "there is no A record `7`");
+    }
+
+    #[tokio::test]
+    async fn duplicates_comment_updates_the_matching_record() {
+        let config = a_config("duplicates = \"comment\"\nmatch-comment = \"home\"");
+        let providers = providers(&config, round_robin()).await;
+
+        assert_ok(&update_records(&config, &providers, &mut None, ip("192.0.2.9"), &[]).await);
+
+        assert_eq!(
+            contents(&providers),
+            pairs(&[
+                ("1", "192.0.2.1"),
+                ("2", "192.0.2.9"),
+                ("3", "192.0.2.3"),
+                ("4", "unrelated")
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicates_previous_updates_the_record_holding_it() {
+        let config = a_config("duplicates = \"previous\"");
+        let providers = providers(&config, round_robin()).await;
+
+        let mut sent = state("192.0.2.3");
+        assert_ok(&update_records(&config, &providers, &mut sent, ip("192.0.2.9"), &[]).await);
+        assert_eq!(zone(&providers)[2].content, a("192.0.2.9"));
+
+        // Nothing holds the previous IP any more, so there's no telling which
+        let mut stale = state("192.0.2.3");
+        let reports = update_records(&config, &providers, &mut stale, ip("192.0.2.10"), &[]).await;
+        assert_err(&reports, "none of them hold the previous IP");
+    }
+
+    #[tokio::test]
+    async fn duplicates_collapse_keeps_the_matching_record() {
+        let config = a_config("duplicates = \"collapse\"");
+        let providers = providers(&config, round_robin()).await;
+
+        assert_ok(&update_records(&config, &providers, &mut None, ip("192.0.2.2"), &[]).await);
+
+        assert_eq!(
+            contents(&providers),
+            pairs(&[("2", "192.0.2.2"), ("4", "unrelated")])
+        );
+    }
+
+    #[tokio::test]
+    async fn entries_sharing_a_name_keep_their_records() {
+        let config = config(&format!(
+            "[[records]]\nzone-identifier = \"{ZONE}\"\ndns-record-name = \"{NAME}\"\nprovider = \"memory\"\nduplicates = \"pin\"\nrecord-id = \"1\"\n\
+             [[records]]\nzone-identifier = \"{ZONE}\"\ndns-record-name = \"{NAME}\"\nprovider = \"memory\"\nduplicates = \"comment\"\nmatch-comment = \"home\"\n"
+        ));
+        let providers = providers(&config, round_robin()).await;
+        let mut state = state("192.0.2.1");
+
+        // Neither entry may take over the other's record through a shared cache
+        for ip in [ip("192.0.2.8"), ip("192.0.2.9")] {
+            assert_ok(&update_records(&config, &providers, &mut state, ip, &[]).await);
+            assert_eq!(
+                contents(&providers),
+                pairs(&[
+                    ("1", &ip.to_string()),
+                    ("2", &ip.to_string()),
+                    ("3", "192.0.2.3"),
+                    ("4", "unrelated")
+                ])
+            );
+        }
+        assert!(cached_ids(&state).is_empty());
+    }
+
+    #[tokio::test]
+    async fn collapse_deletes_duplicates_added_later() {
+        let config = a_config("duplicates = \"collapse\"");
+        let providers = providers(&config, vec![record("1", NAME, a("192.0.2.1"))]).await;
+        let mut state = state("192.0.2.1");
+        assert_ok(&update_records(&config, &providers, &mut state, ip("192.0.2.2"), &[]).await);
+
+        let Provider::Memory(memory) = providers.get(ProviderKind::Memory).unwrap() else {
+            unreachable!()
+        };
+        memory
+            .create_record(
+                ZONE,
+                &RecordParams {
+                    name: NAME.to_string(),
+                    content: a("192.0.2.7"),
+                    ttl: None,
+                    proxied: None,
+                    comment: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_ok(&update_records(&config, &providers, &mut state, ip("192.0.2.3"), &[]).await);
+        assert_eq!(zone(&providers).len(), 1);
+        assert_eq!(zone(&providers)[0].content, a("192.0.2.3"));
+    }
+
+    #[tokio::test]
+    async fn missing_record_is_only_created_if_allowed() {
+        let config = a_config("");
+        let providers = providers(&config, Vec::new()).await;
+        let reports = update_records(&config, &providers, &mut None, ip("192.0.2.1"), &[]).await;
+        assert_err(&reports, "set `create = true`");
+        assert!(zone(&providers).is_empty());
+
+        let config = a_config("create = true\nttl = 120");
+        let mut state = None;
+        assert_ok(&update_records(&config, &providers, &mut state, ip("192.0.2.1"), &[]).await);
+
+        let created = zone(&providers);
+        assert_eq!(created.len(), 1);
+        assert_eq!(
+            (created[0].content.clone(), created[0].ttl),
+            (a("192.0.2.1"), 120)
+        );
+        let state = state.unwrap();
+        assert_eq!(state.owned.len(), 1);
+        assert_eq!(state.owned[0].id, created[0].id);
+        assert_eq!(state.owned[0].name, NAME);
+    }
+
+    #[tokio::test]
+    async fn verify_repairs_drift() {
+        let config = a_config("create = true");
+        let providers = providers(
+            &config,
+            vec![
+                record("1", NAME, a("192.0.2.1")),
+                record("2", "other.example.com", a("192.0.2.1")),
+            ],
+        )
+        .await;
+        let mut state = state("192.0.2.5");
+
+        assert_ok(&verify_records(&config, &providers, &mut state, ip("192.0.2.5"), &[]).await);
+        assert_eq!(zone(&providers)[0].content, a("192.0.2.5"));
+
+        // Nothing to report while the record holds the IP
+        assert!(
+            verify_records(&config, &providers, &mut state, ip("192.0.2.5"), &[])
+                .await
+                .is_empty()
+        );
+
+        let Provider::Memory(memory) = providers.get(ProviderKind::Memory).unwrap() else {
+            unreachable!()
+        };
+        memory.delete_record(ZONE, "1").await.unwrap();
+        assert_ok(&verify_records(&config, &providers, &mut state, ip("192.0.2.5"), &[]).await);
+        let records = zone(&providers);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].content, a("192.0.2.5"));
+        assert_eq!(cached_ids(&state), [records[1].id.as_str()]);
+    }
+
+    #[tokio::test]
+    async fn cached_id_skips_the_lookup() {
+        let config = a_config("");
+        let providers = providers(&config, vec![record("1", NAME, a("192.0.2.1"))]).await;
+        let mut state = state("192.0.2.1");
+        assert_ok(&update_records(&config, &providers, &mut state, ip("192.0.2.2"), &[]).await);
+
+        // A lookup would now fail on the duplicate, the cached ID goes straight to the record
+        let Provider::Memory(memory) = providers.get(ProviderKind::Memory).unwrap() else {
+            unreachable!()
+        };
+        memory
+            .create_record(
+                ZONE,
+                &RecordParams {
+                    name: NAME.to_string(),
+                    content: a("192.0.2.7"),
+                    ttl: None,
+                    proxied: None,
+                    comment: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_ok(&update_records(&config, &providers, &mut state, ip("192.0.2.3"), &[]).await);
+        assert_eq!(
+            contents(&providers),
+            pairs(&[("1", "192.0.2.3"), ("2", "192.0.2.7")])
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_cached_id_falls_back_to_the_lookup() {
+        let config = a_config("");
+        let providers = providers(&config, vec![record("5", NAME, a("192.0.2.1"))]).await;
+        let mut state = state("192.0.2.1");
+        state.as_mut().unwrap().records.push(CachedRecord {
+            zone: ZONE.to_string(),
+            name: NAME.to_string(),
+            kind: "A".to_string(),
+            template: None,
+            id: "1".to_string(),
+        });
+
+        assert_ok(&update_records(&config, &providers, &mut state, ip("192.0.2.2"), &[]).await);
+
+        assert_eq!(contents(&providers), pairs(&[("5", "192.0.2.2")]));
+        assert_eq!(cached_ids(&state), ["5"]);
+    }
+
+    #[test]
+    fn matches_ignores_quoting_but_not_quotes() {
+        let matches = |ours: &str, theirs: &str| other("TXT", ours).matches(&other("TXT", theirs));
+        assert!(matches("v=spf1 -all", "\"v=spf1 -all\""));
+        assert!(matches(
+            "v=spf1 ip4:192.0.2.1 -all",
+            "\"v=spf1 \" \"ip4:192.0.2.1 -all\""
+        ));
+        assert!(matches("a\"b", "\"a\\\"b\""));
+        assert!(matches("a\"b", "a\"b"));
+        assert!(!matches("a\"b", "ab"));
+        assert!(!matches("a\"b", "\"ab\""));
+        assert!(!matches("a b", "\"a\" \"b\""));
+        assert!(
+            other("HTTPS", "1 . alpn=h2 ipv4hint=192.0.2.1")
+                .matches(&other("HTTPS", "1 . alpn=\"h2\" ipv4hint=\"192.0.2.1\""))
+        );
+    }
+
+    #[tokio::test]
+    async fn template_prefix_ignores_quoted_svcparams() {
+        let config = config(
+            "[[records]]\nzone-identifier = \"zone\"\ndns-record-name = \"example.com\"\nprovider = \"memory\"\nrecord-type = \"HTTPS\"\ntemplate = \"1 . alpn=h2 ipv4hint={ipv4}\"\n",
+        );
+        let providers = providers(
+            &config,
+            vec![
+                record(
+                    "1",
+                    "example.com",
+                    other("HTTPS", "1 . alpn=\"h2\" ipv4hint=\"192.0.2.1\""),
+                ),
+                record("2", "example.com", other("HTTPS", "2 . alpn=\"h3\"")),
+            ],
+        )
+        .await;
+        let mut state = state("192.0.2.1");
+
+        assert_ok(&update_records(&config, &providers, &mut state, ip("192.0.2.2"), &[]).await);
+
+        assert_eq!(
+            contents(&providers),
+            pairs(&[
+                ("1", "1 . alpn=h2 ipv4hint=192.0.2.2"),
+                ("2", "2 . alpn=\"h3\"")
+            ])
+        );
+    }
+}
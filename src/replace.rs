@@ -0,0 +1,358 @@
+use std::net::IpAddr;
+
+use anyhow::{Context, Result};
+use tracing::instrument;
+
+use crate::{
+    anyhow_tracing::Tracing,
+    config::Config,
+    provider::{
+        DnsProvider, Providers, Record, RecordContent, RecordParams, RecordReport, resolve_zone,
+    },
+    state::State,
+};
+
+/// Rewrites `previous` to `ip` in every record of the managed records' zones
+/// that contains it, eg. SPF TXT records or HTTPS hints. Does nothing unless
+/// enabled, and only logs the changes in a dry run
+#[instrument(skip(config, providers, state))]
+pub async fn replace_records(
+    config: &Config,
+    providers: &Providers,
+    state: &mut Option<State>,
+    previous: IpAddr,
+    ip: IpAddr,
+) -> Vec<RecordReport> {
+    let replace_config = config.get_replace_config();
+    if !replace_config.get_enabled() || previous == ip {
+        return Vec::new();
+    }
+    let dry_run = replace_config.get_dry_run();
+    tracing::trace!("Replacing {previous} across zones");
+
+    let mut zones = Vec::new();
+    let mut unlisted = Vec::new();
+    for record in config.get_records() {
+        let kind = record.get_provider();
+        if let Ok(provider) = providers.get(kind)
+            && !provider.can_list()
+        {
+            if !unlisted.contains(&kind) {
+                tracing::warn!("The {kind} provider can't list zones, skipping its records");
+                unlisted.push(kind);
+            }
+            continue;
+        }
+        // A zone that can't be found was already reported by the update
+        let Ok(zone) = match providers.get(kind) {
+            Ok(provider) => resolve_zone(config, provider, record, state).await,
+            Err(err) => Err(err),
+        }
+        .with_context(|| format!("skipping the zone of {record}"))
+        .debug() else {
+            continue;
+        };
+        let zone = (kind, zone);
+        if !zones.contains(&zone) {
+            zones.push(zone);
+        }
+    }
+
+    let mut reports = Vec::new();
+    for (kind, zone) in zones {
+        let listed = match providers.get(kind) {
+            Ok(provider) => provider
+                .list_records(&zone)
+                .await
+                .map(|records| (provider, records)),
+            Err(err) => Err(err),
+        }
+        .with_context(|| format!("failed to list records of zone `{zone}`"))
+        .error();
+        let (provider, records) = match listed {
+            Ok(listed) => listed,
+            Err(err) => {
+                reports.push(RecordReport {
+                    record: format!("zone `{zone}`"),
+                    ip,
+                    result: Err(err),
+                });
+                continue;
+            }
+        };
+
+        for record in records {
+            let Some(content) = replace_ip(&record.content, previous, ip) else {
+                continue;
+            };
+            let name = format!("{} ({})", record.name, record.content.kind());
+            if dry_run {
+                tracing::info!(
+                    "Dry run: would change {name} from `{}` to `{content}`",
+                    record.content
+                );
+                continue;
+            }
+
+            tracing::info!("Replacing {previous} in {name}...");
+            let result = write(provider, &zone, &record, content)
+                .await
+                .with_context(|| format!("failed to replace {previous} in {name}"))
+                .error();
+            reports.push(RecordReport {
+                record: name,
+                ip,
+                result,
+            });
+        }
+    }
+
+    reports
+}
+
+async fn write<P: DnsProvider>(
+    provider: &P,
+    zone: &str,
+    record: &Record,
+    content: RecordContent,
+) -> Result<()> {
+    let params = RecordParams {
+        name: record.name.clone(),
+        content,
+        ttl: Some(record.ttl),
+        proxied: record.proxied,
+        comment: None,
+    };
+    provider
+        .update_record(zone, &record.id, &params)
+        .await
+        .context("failed to update DNS record")
+        .debug()
+        .debug_success("Successfully updated DNS record")
+        .error()?;
+
+    Ok(())
+}
+
+/// `content` with every whole occurrence of `previous` replaced by `ip`, or
+/// `None` if it doesn't contain `previous`
+fn replace_ip(content: &RecordContent, previous: IpAddr, ip: IpAddr) -> Option<RecordContent> {
+    match content {
+        RecordContent::A(_) | RecordContent::Aaaa(_) => {
+            (content.ip() == Some(previous)).then(|| RecordContent::from(ip))
+        }
+        RecordContent::Other { kind, content } => {
+            let previous_text = previous.to_string();
+            let mut replaced = String::with_capacity(content.len());
+            let mut copied = 0;
+            let mut found = false;
+            for (index, _) in content.match_indices(&previous_text) {
+                let end = index + previous_text.len();
+                if is_boundary(&content[..index], &content[end..], previous) {
+                    replaced.push_str(&content[copied..index]);
+                    replaced.push_str(&ip.to_string());
+                    copied = end;
+                    found = true;
+                }
+            }
+            replaced.push_str(&content[copied..]);
+
+            found.then(|| RecordContent::Other {
+                kind: kind.clone(),
+                content: replaced,
+            })
+        }
+    }
+}
+
+/// Whether a match of `ip` between `before` and `after` is the whole address,
+/// rather than part of a longer one (eg. `1.2.3.4` in `11.2.3.45`)
+fn is_boundary(before: &str, after: &str, ip: IpAddr) -> bool {
+    let mut after = after.chars();
+    let (next, next_but_one) = (after.next(), after.next());
+    match ip {
+        // A trailing `.` may just end a sentence, but not be followed by another octet
+        IpAddr::V4(_) => {
+            !(before
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_ascii_digit() || c == '.')
+                || next.is_some_and(|c| c.is_ascii_digit())
+                || next == Some('.') && next_but_one.is_some_and(|c| c.is_ascii_digit()))
+        }
+        // The hex digits and colons right before the match would be the start
+        // of a longer address, unless they finish a word like SPF's `ip6:`
+        IpAddr::V6(_) => {
+            let start = before
+                .char_indices()
+                .rev()
+                .find(|(_, c)| !(c.is_ascii_hexdigit() || *c == ':'))
+                .map_or(0, |(index, c)| index + c.len_utf8());
+            let mut run = &before[start..];
+            if before[..start]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_alphanumeric())
+            {
+                run = run.split_once(':').map_or(run, |(_, rest)| rest);
+            }
+            run.is_empty() && !next.is_some_and(|c| c.is_ascii_hexdigit() || c == ':')
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::MemoryProvider,
+        provider::{Provider, ProviderKind},
+    };
+
+    const ZONE: &str = "zone";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn txt(content: &str) -> RecordContent {
+        RecordContent::Other {
+            kind: "TXT".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    fn replaced(content: &str, previous: &str, new: &str) -> Option<String> {
+        replace_ip(&txt(content), ip(previous), ip(new)).map(|content| content.to_string())
+    }
+
+    #[test]
+    fn replaces_address_records_holding_the_ip() {
+        let a = RecordContent::from(ip("192.0.2.1"));
+        assert_eq!(
+            replace_ip(&a, ip("192.0.2.1"), ip("192.0.2.2")),
+            Some(RecordContent::from(ip("192.0.2.2")))
+        );
+        assert_eq!(replace_ip(&a, ip("192.0.2.3"), ip("192.0.2.2")), None);
+    }
+
+    #[test]
+    fn only_replaces_whole_ipv4_addresses() {
+        assert_eq!(
+            replaced(
+                "v=spf1 ip4:1.2.3.4 ip4:11.2.3.45 -all",
+                "1.2.3.4",
+                "5.6.7.8"
+            ),
+            Some("v=spf1 ip4:5.6.7.8 ip4:11.2.3.45 -all".to_string())
+        );
+        assert_eq!(replaced("ip4:11.2.3.45", "1.2.3.4", "5.6.7.8"), None);
+        assert_eq!(replaced("ip4:1.2.3.4.5", "1.2.3.4", "5.6.7.8"), None);
+        assert_eq!(replaced("ip4:0.1.2.3.4", "1.2.3.4", "5.6.7.8"), None);
+        assert_eq!(
+            replaced("ip4:1.2.3.4/24", "1.2.3.4", "5.6.7.8"),
+            Some("ip4:5.6.7.8/24".to_string())
+        );
+    }
+
+    #[test]
+    fn a_trailing_dot_ends_the_address() {
+        assert_eq!(
+            replaced("v=spf1 ip4:1.2.3.4. -all", "1.2.3.4", "5.6.7.8"),
+            Some("v=spf1 ip4:5.6.7.8. -all".to_string())
+        );
+        assert_eq!(
+            replaced("mail is on 1.2.3.4.", "1.2.3.4", "5.6.7.8"),
+            Some("mail is on 5.6.7.8.".to_string())
+        );
+    }
+
+    #[test]
+    fn only_replaces_whole_ipv6_addresses() {
+        assert_eq!(
+            replaced(
+                "ip6:2001:db8::1 ip6:2001:db8::10 ip6:2001:db8::1:5 -all",
+                "2001:db8::1",
+                "2001:db8::2"
+            ),
+            Some("ip6:2001:db8::2 ip6:2001:db8::10 ip6:2001:db8::1:5 -all".to_string())
+        );
+        // `db8::1` is only the tail of `2001:db8::1`
+        assert_eq!(replaced("ip6:2001:db8::1", "db8::1", "db8::2"), None);
+        assert_eq!(
+            replaced("fe80:2001:db8::1", "2001:db8::1", "2001:db8::2"),
+            None
+        );
+        assert_eq!(
+            replaced(
+                "ipv6hint=2001:db8::1,2001:db8::3",
+                "2001:db8::1",
+                "2001:db8::2"
+            ),
+            Some("ipv6hint=2001:db8::2,2001:db8::3".to_string())
+        );
+    }
+
+    async fn replace(dry_run: bool) -> Vec<Record> {
+        let config = Config::parse(&format!(
+            "active = true\n[ip-find]\nfinders = []\n[cloudflare]\napi-key = \"\"\n[replace]\nenabled = true\ndry-run = {dry_run}\n\
+             [[records]]\nzone-identifier = \"{ZONE}\"\ndns-record-name = \"www.example.com\"\nprovider = \"memory\"\n"
+        ))
+        .unwrap();
+        let records = vec![
+            Record {
+                id: "1".to_string(),
+                name: "example.com".to_string(),
+                content: txt("v=spf1 ip4:192.0.2.1 -all"),
+                ttl: 60,
+                proxied: None,
+                comment: None,
+                modified: None,
+            },
+            Record {
+                id: "2".to_string(),
+                name: "example.com".to_string(),
+                content: txt("v=spf1 ip4:192.0.2.10 -all"),
+                ttl: 60,
+                proxied: None,
+                comment: None,
+                modified: None,
+            },
+        ];
+        let mut providers = Providers::new(&config).await.unwrap();
+        providers.insert(
+            ProviderKind::Memory,
+            Provider::Memory(MemoryProvider::with_records(ZONE, records)),
+        );
+
+        let reports = replace_records(
+            &config,
+            &providers,
+            &mut None,
+            ip("192.0.2.1"),
+            ip("192.0.2.2"),
+        )
+        .await;
+        assert_eq!(reports.len(), if dry_run { 0 } else { 1 });
+        assert!(reports.iter().all(|report| report.result.is_ok()));
+
+        match providers.get(ProviderKind::Memory).unwrap() {
+            Provider::Memory(memory) => memory.records(ZONE),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn replaces_the_previous_ip_across_the_zone() {
+        let records = replace(false).await;
+        assert_eq!(records[0].content, txt("v=spf1 ip4:192.0.2.2 -all"));
+        assert_eq!(records[1].content, txt("v=spf1 ip4:192.0.2.10 -all"));
+    }
+
+    #[tokio::test]
+    async fn dry_run_writes_nothing() {
+        let records = replace(true).await;
+        assert_eq!(records[0].content, txt("v=spf1 ip4:192.0.2.1 -all"));
+        assert_eq!(records[1].content, txt("v=spf1 ip4:192.0.2.10 -all"));
+    }
+}
@@ -0,0 +1,666 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use ring::hmac;
+use tracing::instrument;
+
+use crate::{
+    anyhow_tracing::Tracing,
+    config::Rfc2136Config,
+    dns::{
+        self, CLASS_ANY, CLASS_IN, CLASS_NONE, Message, Reader, ResourceRecord, TYPE_A, TYPE_AAAA,
+        TYPE_SOA, TYPE_TSIG, TYPE_TXT,
+    },
+    provider::{DnsProvider, Record, RecordContent, RecordParams},
+};
+
+const HMAC_SHA256: &str = "hmac-sha256";
+const FUDGE: u16 = 300;
+
+/// [`DnsProvider`] that talks RFC 2136 dynamic updates to an authoritative
+/// server such as BIND or Knot
+///
+/// RFC 2136 has no notion of record IDs, so a record's ID is its name, type
+/// and content (eg. `www.example.com A 192.0.2.1`), which is exactly what an
+/// update needs to delete it again
+pub struct Rfc2136Provider {
+    server: SocketAddr,
+    key: Option<TsigKey>,
+    timeout: Duration,
+    ttl: u32,
+}
+
+struct TsigKey {
+    name: String,
+    key: hmac::Key,
+}
+
+impl Rfc2136Provider {
+    #[instrument]
+    pub(crate) async fn new(rfc_config: &Rfc2136Config) -> Result<Self> {
+        let server = rfc_config
+            .get_server()
+            .await
+            .context("failed to resolve RFC 2136 server")
+            .error()?;
+
+        let key = match rfc_config.get_key()? {
+            Some((name, secret)) => Some(TsigKey {
+                name: name.to_string(),
+                key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            }),
+            None => {
+                tracing::warn!("No TSIG key configured, RFC 2136 updates will be unsigned");
+                None
+            }
+        };
+
+        Ok(Self {
+            server,
+            key,
+            timeout: Duration::from_secs(rfc_config.get_timeout() as u64),
+            ttl: rfc_config.get_ttl(),
+        })
+    }
+
+    /// Signs and sends `request`, checking the response is a correctly signed reply
+    async fn send(&self, mut request: Message) -> Result<Message> {
+        let request_mac = match &self.key {
+            Some(key) => Some(key.sign(&mut request)?),
+            None => None,
+        };
+
+        let raw = dns::exchange(self.server, &request.encode(), self.timeout)
+            .await
+            .with_context(|| format!("failed to reach DNS server {}", self.server))?;
+        let (response, tsig_offset) =
+            Message::decode_with_offset(&raw).context("failed to decode DNS response")?;
+        dns::check_response(&request, &response)?;
+
+        if let (Some(key), Some(request_mac)) = (&self.key, request_mac) {
+            key.verify(&raw, &response, tsig_offset, &request_mac)
+                .context("failed to verify TSIG signature of DNS response")?;
+        }
+
+        Ok(response)
+    }
+
+    async fn update(&self, zone: &str, records: Vec<ResourceRecord>) -> Result<()> {
+        let mut request = Message::update(zone);
+        request.authority = records;
+        let response = self.send(request).await?;
+        anyhow::ensure!(
+            response.rcode() == 0,
+            "DNS server responded with {}",
+            dns::rcode_name(response.rcode())
+        );
+        Ok(())
+    }
+
+    fn to_rr(
+        &self,
+        name: &str,
+        content: &RecordContent,
+        ttl: Option<u32>,
+    ) -> Result<ResourceRecord> {
+        let (rtype, rdata) = match content {
+            RecordContent::A(ip) => (TYPE_A, ip.octets().to_vec()),
+            RecordContent::Aaaa(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+            RecordContent::Other { kind, content } if kind == "TXT" => {
+                (TYPE_TXT, dns::encode_txt(content))
+            }
+            RecordContent::Other { kind, .. } => {
+                anyhow::bail!("`{kind}` records are not supported by the RFC 2136 provider")
+            }
+        };
+        Ok(ResourceRecord {
+            name: name.to_string(),
+            rtype,
+            class: CLASS_IN,
+            ttl: ttl.unwrap_or(self.ttl),
+            rdata,
+        })
+    }
+}
+
+impl DnsProvider for Rfc2136Provider {
+    #[instrument(skip(self))]
+    async fn find_records(&self, _zone: &str, name: &str) -> Result<Vec<Record>> {
+        let mut records = Vec::new();
+        for qtype in [TYPE_A, TYPE_AAAA, TYPE_TXT] {
+            let response = self
+                .send(Message::query(name, qtype, CLASS_IN))
+                .await
+                .with_context(|| format!("failed to query `{name}`"))
+                .debug()?;
+            match response.rcode() {
+                0 => {}
+                // The name doesn't exist at all, so neither does any record type
+                3 => break,
+                rcode => anyhow::bail!(
+                    "DNS server responded to query for `{name}` with {}",
+                    dns::rcode_name(rcode)
+                ),
+            }
+
+            for answer in response.answers.into_iter().filter(|answer| {
+                answer.rtype == qtype
+                    && answer.name.eq_ignore_ascii_case(name.trim_end_matches('.'))
+            }) {
+                let content = match (answer.rtype, answer.rdata.len()) {
+                    (TYPE_A, 4) => {
+                        RecordContent::A(<[u8; 4]>::try_from(answer.rdata.as_slice())?.into())
+                    }
+                    (TYPE_AAAA, 16) => {
+                        RecordContent::Aaaa(<[u8; 16]>::try_from(answer.rdata.as_slice())?.into())
+                    }
+                    (TYPE_TXT, _) => RecordContent::Other {
+                        kind: "TXT".to_string(),
+                        content: answer.txt(),
+                    },
+                    _ => continue,
+                };
+                records.push(Record {
+                    id: format_id(&answer.name, &content),
+                    name: answer.name,
+                    content,
+                    ttl: answer.ttl,
+                    proxied: None,
+                    comment: None,
+                    modified: None,
+                });
+            }
+        }
+
+        Ok(records)
+    }
+
+    async fn list_records(&self, _zone: &str) -> Result<Vec<Record>> {
+        anyhow::bail!("listing a whole zone (AXFR) is not supported by the RFC 2136 provider")
+    }
+
+    /// Zones are identified by their name here, so this only checks the
+    /// server has an SOA record at `name`
+    #[instrument(skip(self))]
+    async fn find_zone(&self, name: &str) -> Result<Option<String>> {
+        let response = self
+            .send(Message::query(name, TYPE_SOA, CLASS_IN))
+            .await
+            .with_context(|| format!("failed to query SOA of `{name}`"))
+            .debug()?;
+        match response.rcode() {
+            0 => {}
+            3 => return Ok(None),
+            rcode => anyhow::bail!(
+                "DNS server responded to SOA query for `{name}` with {}",
+                dns::rcode_name(rcode)
+            ),
+        }
+
+        let name = name.trim_end_matches('.');
+        Ok(response
+            .answers
+            .iter()
+            .any(|answer| answer.rtype == TYPE_SOA && answer.name.eq_ignore_ascii_case(name))
+            .then(|| name.to_string()))
+    }
+
+    #[instrument(skip(self))]
+    async fn create_record(&self, zone: &str, params: &RecordParams) -> Result<Record> {
+        let add = self.to_rr(&params.name, &params.content, params.ttl)?;
+        let ttl = add.ttl;
+        self.update(zone, vec![add])
+            .await
+            .context("failed to add DNS record")
+            .debug()
+            .debug_success("Successfully added DNS record")
+            .error()?;
+
+        Ok(Record {
+            id: format_id(&params.name, &params.content),
+            name: params.name.clone(),
+            content: params.content.clone(),
+            ttl,
+            proxied: None,
+            comment: None,
+            modified: None,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn update_record(&self, zone: &str, id: &str, params: &RecordParams) -> Result<Record> {
+        let (name, content) = parse_id(id)?;
+        let mut delete = self.to_rr(name, &content, Some(0))?;
+        delete.class = CLASS_NONE;
+        let add = self.to_rr(&params.name, &params.content, params.ttl)?;
+        let ttl = add.ttl;
+
+        // Both halves go in one message, so the server applies them atomically
+        self.update(zone, vec![delete, add])
+            .await
+            .context("failed to replace DNS record")
+            .debug()
+            .debug_success("Successfully replaced DNS record")
+            .error()?;
+
+        Ok(Record {
+            id: format_id(&params.name, &params.content),
+            name: params.name.clone(),
+            content: params.content.clone(),
+            ttl,
+            proxied: None,
+            comment: None,
+            modified: None,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_record(&self, zone: &str, id: &str) -> Result<()> {
+        let (name, content) = parse_id(id)?;
+        let mut delete = self.to_rr(name, &content, Some(0))?;
+        delete.class = CLASS_NONE;
+        self.update(zone, vec![delete])
+            .await
+            .context("failed to delete DNS record")
+            .debug()
+            .debug_success("Successfully deleted DNS record")
+            .error()
+    }
+
+    /// An ID is the record's content, so changes with every update
+    fn has_stable_ids(&self) -> bool {
+        false
+    }
+
+    /// That would take an AXFR, which servers rarely allow for update keys
+    fn can_list(&self) -> bool {
+        false
+    }
+}
+
+fn format_id(name: &str, content: &RecordContent) -> String {
+    format!("{name} {} {content}", content.kind())
+}
+
+fn parse_id(id: &str) -> Result<(&str, RecordContent)> {
+    let mut parts = id.splitn(3, ' ');
+    let (Some(name), Some(kind), Some(content)) = (parts.next(), parts.next(), parts.next()) else {
+        anyhow::bail!("invalid RFC 2136 record id `{id}`");
+    };
+    let content = match kind {
+        "A" | "AAAA" => RecordContent::from(
+            content
+                .parse::<IpAddr>()
+                .with_context(|| format!("invalid address in record id `{id}`"))?,
+        ),
+        kind => RecordContent::Other {
+            kind: kind.to_string(),
+            content: content.to_string(),
+        },
+    };
+    Ok((name, content))
+}
+
+impl TsigKey {
+    /// Appends a TSIG record (RFC 8945) to `message`, returning its MAC
+    fn sign(&self, message: &mut Message) -> Result<Vec<u8>> {
+        self.sign_reply(message, &[])
+    }
+
+    /// Signs `message` as a reply to one signed with `request_mac`, which
+    /// is empty for requests
+    fn sign_reply(&self, message: &mut Message, request_mac: &[u8]) -> Result<Vec<u8>> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("system clock is before the unix epoch")?
+            .as_secs();
+        Ok(self.sign_at(message, request_mac, time))
+    }
+
+    /// Signs `message` as of `time`, in seconds since the unix epoch
+    fn sign_at(&self, message: &mut Message, request_mac: &[u8], time: u64) -> Vec<u8> {
+        let mut signed = Vec::new();
+        if !request_mac.is_empty() {
+            signed.extend_from_slice(&(request_mac.len() as u16).to_be_bytes());
+            signed.extend_from_slice(request_mac);
+        }
+        signed.extend_from_slice(&message.encode());
+        self.append_variables(&mut signed, time, FUDGE, 0, &[]);
+        let mac = hmac::sign(&self.key, &signed).as_ref().to_vec();
+
+        let mut rdata = Vec::new();
+        dns::encode_name(&mut rdata, HMAC_SHA256);
+        rdata.extend_from_slice(&time.to_be_bytes()[2..]);
+        rdata.extend_from_slice(&FUDGE.to_be_bytes());
+        rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&mac);
+        rdata.extend_from_slice(&message.id.to_be_bytes());
+        rdata.extend_from_slice(&0u16.to_be_bytes());
+        rdata.extend_from_slice(&0u16.to_be_bytes());
+
+        message.additional.push(ResourceRecord {
+            name: self.name.clone(),
+            rtype: TYPE_TSIG,
+            class: CLASS_ANY,
+            ttl: 0,
+            rdata,
+        });
+
+        mac
+    }
+
+    /// Checks the TSIG record at `tsig_offset` in the raw `response` was
+    /// produced with this key in reply to a request signed with `request_mac`
+    fn verify(
+        &self,
+        raw: &[u8],
+        response: &Message,
+        tsig_offset: Option<usize>,
+        request_mac: &[u8],
+    ) -> Result<()> {
+        let (Some(tsig), Some(offset)) = (
+            response
+                .additional
+                .last()
+                .filter(|record| record.rtype == TYPE_TSIG),
+            tsig_offset,
+        ) else {
+            anyhow::ensure!(
+                response.rcode() != 0,
+                "DNS server sent an unsigned response"
+            );
+            // Errors such as NOTAUTH are allowed to come back unsigned
+            return Ok(());
+        };
+
+        anyhow::ensure!(
+            tsig.name
+                .eq_ignore_ascii_case(self.name.trim_end_matches('.')),
+            "response was signed with unexpected key `{}`",
+            tsig.name
+        );
+
+        let mut reader = Reader::new(&tsig.rdata);
+        let algorithm = reader.name()?;
+        anyhow::ensure!(
+            algorithm.eq_ignore_ascii_case(HMAC_SHA256),
+            "response was signed with unexpected algorithm `{algorithm}`"
+        );
+        let time_bytes = reader.take(6)?;
+        let time = time_bytes
+            .iter()
+            .fold(0u64, |time, byte| (time << 8) | *byte as u64);
+        let fudge = reader.u16()?;
+        let mac_len = reader.u16()? as usize;
+        let mac = reader.take(mac_len)?;
+        let original_id = reader.u16()?;
+        let error = reader.u16()?;
+        let other_len = reader.u16()? as usize;
+        let other = reader.take(other_len)?;
+
+        anyhow::ensure!(
+            error == 0,
+            "DNS server rejected TSIG signature with {}",
+            match error {
+                16 => "BADSIG",
+                17 => "BADKEY",
+                18 => "BADTIME",
+                _ => "an unknown error",
+            }
+        );
+
+        // The MAC covers the request MAC, then the response as it was before
+        // the TSIG record was added, then the TSIG variables
+        let mut signed = Vec::with_capacity(raw.len() + request_mac.len());
+        signed.extend_from_slice(&(request_mac.len() as u16).to_be_bytes());
+        signed.extend_from_slice(request_mac);
+        let mut unsigned = raw[..offset].to_vec();
+        unsigned[..2].copy_from_slice(&original_id.to_be_bytes());
+        let additional_count = u16::from_be_bytes([unsigned[10], unsigned[11]]) - 1;
+        unsigned[10..12].copy_from_slice(&additional_count.to_be_bytes());
+        signed.extend_from_slice(&unsigned);
+        self.append_variables(&mut signed, time, fudge, error, other);
+
+        hmac::verify(&self.key, &signed, mac)
+            .map_err(|_| anyhow::anyhow!("TSIG signature of DNS response is invalid"))
+    }
+
+    fn append_variables(&self, buf: &mut Vec<u8>, time: u64, fudge: u16, error: u16, other: &[u8]) {
+        dns::encode_name(buf, &self.name);
+        buf.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        dns::encode_name(buf, HMAC_SHA256);
+        buf.extend_from_slice(&time.to_be_bytes()[2..]);
+        buf.extend_from_slice(&fudge.to_be_bytes());
+        buf.extend_from_slice(&error.to_be_bytes());
+        buf.extend_from_slice(&(other.len() as u16).to_be_bytes());
+        buf.extend_from_slice(other);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"a fairly secret key for testing!";
+
+    fn key(secret: &[u8]) -> TsigKey {
+        TsigKey {
+            name: "ddns-key.".to_string(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    /// The MAC out of the TSIG record of a signed `message`
+    fn tsig_mac(message: &Message) -> Vec<u8> {
+        let tsig = message.additional.last().unwrap();
+        assert_eq!(tsig.rtype, TYPE_TSIG);
+        let mut reader = Reader::new(&tsig.rdata);
+        reader.name().unwrap();
+        reader.take(8).unwrap();
+        let len = reader.u16().unwrap() as usize;
+        reader.take(len).unwrap().to_vec()
+    }
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Signs `response` as a reply to `request_mac` and encodes it
+    fn signed_reply(key: &TsigKey, response: &mut Message, request_mac: &[u8]) -> Vec<u8> {
+        key.sign_reply(response, request_mac).unwrap();
+        response.encode()
+    }
+
+    fn reply_to(request: &Message) -> Message {
+        Message {
+            id: request.id,
+            flags: request.flags | 0x8000,
+            questions: request.questions.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Checks the MAC construction against HMAC-SHA256 TSIG values computed
+    /// from RFC 8945 alone, for an update of `www.example.com A 192.0.2.1`
+    /// signed at 1700000000 and its reply
+    #[test]
+    fn known_answer() {
+        let key = key(SECRET);
+        let mut request = Message::update("example.com");
+        request.id = 0x1234;
+        request.authority.push(ResourceRecord {
+            name: "www.example.com".to_string(),
+            rtype: TYPE_A,
+            class: CLASS_IN,
+            ttl: 300,
+            rdata: vec![192, 0, 2, 1],
+        });
+        let request_mac = key.sign_at(&mut request, &[], 1_700_000_000);
+        assert_eq!(
+            request_mac,
+            hex("03804e7c61851df6ff3ace9b7c0bc176c089538e5d91e0e15eac8d87ab7b970f")
+        );
+
+        let raw = hex(concat!(
+            "1234a8000001000000000001076578616d706c6503636f6d0000060001",
+            "0864646e732d6b65790000fa00ff00000000003d0b686d61632d73686132",
+            "35360000006553f100012c0020c2d871cde7a20f09dc28d0be8608ee8ed7",
+            "5d2933267da11992a652357a3d6c08123400000000",
+        ));
+        let (response, offset) = Message::decode_with_offset(&raw).unwrap();
+        key.verify(&raw, &response, offset, &request_mac).unwrap();
+
+        let mut reply = reply_to(&request);
+        key.sign_at(&mut reply, &request_mac, 1_700_000_000);
+        assert_eq!(reply.encode(), raw);
+    }
+
+    #[test]
+    fn sign_then_verify() {
+        let key = key(SECRET);
+        let mut request = Message::update("example.com");
+        let request_mac = key.sign(&mut request).unwrap();
+        assert_eq!(request_mac.len(), 32);
+        assert_eq!(tsig_mac(&request), request_mac);
+
+        let raw = signed_reply(&key, &mut reply_to(&request), &request_mac);
+        let (response, offset) = Message::decode_with_offset(&raw).unwrap();
+        key.verify(&raw, &response, offset, &request_mac).unwrap();
+
+        // Tampering with the message, the key or the request MAC breaks it
+        let mut tampered = raw.clone();
+        tampered[3] ^= 0x01;
+        let (response, offset) = Message::decode_with_offset(&tampered).unwrap();
+        assert!(
+            key.verify(&tampered, &response, offset, &request_mac)
+                .is_err()
+        );
+
+        let (response, offset) = Message::decode_with_offset(&raw).unwrap();
+        assert!(
+            self::key(b"some other key")
+                .verify(&raw, &response, offset, &request_mac)
+                .is_err()
+        );
+        assert!(key.verify(&raw, &response, offset, &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn unsigned_responses_are_only_allowed_for_errors() {
+        let key = key(SECRET);
+        let mut request = Message::update("example.com");
+        let request_mac = key.sign(&mut request).unwrap();
+
+        let mut response = reply_to(&request);
+        let raw = response.encode();
+        assert!(key.verify(&raw, &response, None, &request_mac).is_err());
+
+        // NOTAUTH
+        response.flags |= 9;
+        let raw = response.encode();
+        key.verify(&raw, &response, None, &request_mac).unwrap();
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        let content = RecordContent::A("192.0.2.1".parse().unwrap());
+        let id = format_id("www.example.com", &content);
+        assert_eq!(id, "www.example.com A 192.0.2.1");
+        assert_eq!(parse_id(&id).unwrap(), ("www.example.com", content));
+        assert!(parse_id("www.example.com A").is_err());
+        assert!(parse_id("www.example.com A nope").is_err());
+    }
+
+    #[tokio::test]
+    async fn update_record_exchange() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = responder.local_addr().unwrap();
+        let config: Rfc2136Config = toml::from_str(&format!(
+            "server = \"{server}\"\nkey-name = \"ddns-key.\"\nkey-secret = \"{}\"\nttl = 120\n",
+            base64::Engine::encode(&base64::engine::general_purpose::STANDARD, SECRET)
+        ))
+        .unwrap();
+        let provider = Rfc2136Provider::new(&config).await.unwrap();
+
+        let answer = tokio::spawn(async move {
+            let mut buf = vec![0; 4096];
+            let (len, client) = responder.recv_from(&mut buf).await.unwrap();
+            let (request, _) = Message::decode_with_offset(&buf[..len]).unwrap();
+            let mut response = reply_to(&request);
+            let raw = signed_reply(&key(SECRET), &mut response, &tsig_mac(&request));
+            responder.send_to(&raw, client).await.unwrap();
+            request
+        });
+
+        let params = RecordParams {
+            name: "www.example.com".to_string(),
+            content: RecordContent::A("192.0.2.2".parse().unwrap()),
+            ttl: None,
+            proxied: None,
+            comment: None,
+        };
+        let record = provider
+            .update_record("example.com", "www.example.com A 192.0.2.1", &params)
+            .await
+            .unwrap();
+        assert_eq!(record.id, "www.example.com A 192.0.2.2");
+        assert_eq!(record.ttl, 120);
+
+        let request = answer.await.unwrap();
+        assert_eq!(request.opcode(), dns::OPCODE_UPDATE);
+        assert_eq!(request.questions[0].name, "example.com");
+        assert_eq!(request.questions[0].qtype, TYPE_SOA);
+        let [delete, add] = request.authority.as_slice() else {
+            panic!("expected a delete and an add, got {:?}", request.authority);
+        };
+        assert_eq!(
+            (delete.class, delete.ttl, delete.rdata.as_slice()),
+            (CLASS_NONE, 0, &[192, 0, 2, 1][..])
+        );
+        assert_eq!(
+            (add.class, add.ttl, add.rdata.as_slice()),
+            (CLASS_IN, 120, &[192, 0, 2, 2][..])
+        );
+        assert_eq!(request.additional.last().unwrap().name, "ddns-key");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_forged_response() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = responder.local_addr().unwrap();
+        let config: Rfc2136Config = toml::from_str(&format!(
+            "server = \"{server}\"\nkey-name = \"ddns-key.\"\nkey-secret = \"{}\"\ntimeout = 2\n",
+            base64::Engine::encode(&base64::engine::general_purpose::STANDARD, SECRET)
+        ))
+        .unwrap();
+        let provider = Rfc2136Provider::new(&config).await.unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = vec![0; 4096];
+            let (len, client) = responder.recv_from(&mut buf).await.unwrap();
+            let (request, _) = Message::decode_with_offset(&buf[..len]).unwrap();
+            let mut response = reply_to(&request);
+            let raw = signed_reply(
+                &key(b"not the shared secret"),
+                &mut response,
+                &tsig_mac(&request),
+            );
+            responder.send_to(&raw, client).await.unwrap();
+        });
+
+        let err = provider
+            .delete_record("example.com", "www.example.com A 192.0.2.1")
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("TSIG"), "{err:#}");
+    }
+}
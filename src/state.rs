@@ -1,8 +1,77 @@
-use std::net::Ipv4Addr;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct State {
     pub(crate) last_sent_ip: Option<Ipv4Addr>,
+    pub(crate) last_sent_ipv6: Option<Ipv6Addr>,
+    /// Unix timestamps of when the records were last known to hold each IP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_sent_ip_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_sent_ipv6_at: Option<u64>,
+    /// Runs since the live records were last checked for drift
+    #[serde(default)]
+    pub(crate) unverified_runs: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pending_ip: Option<Observation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pending_ipv6: Option<Observation>,
+    /// Zone IDs looked up by the `zone-name`, or the record name the zone
+    /// was inferred from
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) zones: BTreeMap<String, String>,
+    /// IDs of the managed records, so they can be updated without listing
+    /// them first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) records: Vec<CachedRecord>,
+    /// Records that were created by us rather than found
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) owned: Vec<OwnedRecord>,
+}
+
+/// A new IP that has been seen but not yet published
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Observation {
+    pub(crate) ip: IpAddr,
+    /// Unix timestamp of the first check that saw it
+    pub(crate) first_seen: u64,
+    /// Consecutive checks that have seen it
+    pub(crate) seen: u32,
+}
+
+/// Where a managed record was last found
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub(crate) struct CachedRecord {
+    pub(crate) zone: String,
+    pub(crate) name: String,
+    pub(crate) kind: String,
+    /// Tells apart templated records sharing a name and type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) template: Option<String>,
+    pub(crate) id: String,
+}
+
+/// A record created because `create` was set and it didn't exist
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub(crate) struct OwnedRecord {
+    pub(crate) zone: String,
+    pub(crate) name: String,
+    pub(crate) kind: String,
+    pub(crate) id: String,
+    /// Unix timestamp of when it was created
+    pub(crate) created: u64,
+}
+
+/// Seconds since the Unix epoch, the timestamps kept in [`State`]
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }